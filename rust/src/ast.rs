@@ -1,5 +1,6 @@
 use serde::Serialize;
 use std::collections::HashMap;
+use std::fmt;
 use std::ops::{Deref, DerefMut};
 use std::path::PathBuf;
 use std::sync::Arc;
@@ -35,13 +36,13 @@ impl From<Element> for Doc {
 #[derive(Serialize, Debug, Clone, PartialEq, Eq)]
 #[serde(untagged)]
 pub enum Item {
-    Text { text: String, pos: Pos },
+    Text { text: String, pos: Pos, end: Pos },
     Element(Element),
 }
 
 impl Item {
-    pub fn new_text(text: String, pos: Pos) -> Self {
-        Item::Text { text, pos }
+    pub fn new_text(text: String, pos: Pos, end: Pos) -> Self {
+        Item::Text { text, pos, end }
     }
 
     pub fn get_text(&self) -> Option<&str> {
@@ -79,13 +80,16 @@ impl From<Element> for Item {
 pub fn concat_texts(items: Vec<Item>) -> Vec<Item> {
     let mut res = vec![];
     for item in items.into_iter() {
-        if let Item::Text { text, .. } = &item {
+        if let Item::Text { text, end, .. } = &item {
             if !text.is_empty() {
                 if let Some(Item::Text {
-                    text: prev_text, ..
+                    text: prev_text,
+                    end: prev_end,
+                    ..
                 }) = res.last_mut()
                 {
                     prev_text.push_str(&text);
+                    *prev_end = end.clone();
                 } else {
                     res.push(item);
                 }
@@ -104,7 +108,17 @@ pub struct Element {
     pub named_args: HashMap<String, Doc>,
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub pos_args: Vec<Doc>,
+    /// The source ranges of the named arguments, including the
+    /// brackets.
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub named_arg_ranges: HashMap<String, Range>,
+    /// The source ranges of the positional arguments, including the
+    /// braces. For a `\begin`/`\end` element, the range of the last
+    /// argument is the text between the tags.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub pos_arg_ranges: Vec<Range>,
     pub pos: Pos,
+    pub end: Pos,
 }
 
 impl Element {
@@ -125,4 +139,41 @@ pub struct Pos {
     pub filename: Option<Filename>,
     pub line: u32,
     pub column: u32,
+    /// The byte offset in the source file.
+    pub offset: usize,
+}
+
+impl Pos {
+    /// Return the position following the text `s` starting at this
+    /// position.
+    pub fn advance(&self, s: &str) -> Pos {
+        let mut pos = self.clone();
+        for c in s.chars() {
+            if c == '\n' {
+                pos.line += 1;
+                pos.column = 0;
+            } else {
+                pos.column += 1;
+            }
+        }
+        pos.offset += s.len();
+        pos
+    }
+}
+
+/// The source range of a piece of syntax, from the start position up
+/// to (but not including) the end position.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Range {
+    pub start: Pos,
+    pub end: Pos,
+}
+
+impl fmt::Display for Pos {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(filename) = &self.filename {
+            write!(f, "{}:", filename.display())?;
+        }
+        write!(f, "{}:{}", self.line + 1, self.column + 1)
+    }
 }
@@ -4,7 +4,13 @@ use std::collections::HashMap;
 
 lazy_static! {
     pub static ref SCHEMA: Schema = {
-        let inline = Pattern::Choice(vec![
+        /* Elements refer to the inline and block groups by name, so
+        that schemas extending this one can add alternatives to
+        them. */
+        let inline = Pattern::reference("inline");
+        let block = Pattern::reference("block");
+
+        let inline_group = Pattern::Choice(vec![
             Pattern::Text,
             Pattern::element("emph"),
             Pattern::element("strong"),
@@ -19,7 +25,7 @@ lazy_static! {
             Pattern::element("replaceable"),
         ]);
 
-        let block = Pattern::Choice(vec![
+        let block_group = Pattern::Choice(vec![
             Pattern::para(Pattern::many1(inline.clone())),
             Pattern::element("dinkus"),
             Pattern::element("listing"),
@@ -32,10 +38,9 @@ lazy_static! {
 
         let title = Pattern::many1(inline.clone());
 
-        let uri_string = Pattern::Text;
+        let uri_string = Pattern::Uri;
 
-        // FIXME: should be a distinct type for validation.
-        let id_string = Pattern::Text;
+        let id_string = Pattern::Ident;
 
         let mut schema = Schema {
             start: Pattern::Choice(vec![
@@ -46,9 +51,13 @@ lazy_static! {
                 Pattern::element("chapter"),
                 Pattern::element("section"),
             ]),
+            patterns: HashMap::new(),
             elements: HashMap::new()
         };
 
+        schema.patterns.insert("inline".to_string(), inline_group);
+        schema.patterns.insert("block".to_string(), block_group);
+
         schema.add_element(
             "book",
             vec![
@@ -222,6 +231,30 @@ lazy_static! {
             ]
         );
 
+        schema.add_named_arg(
+            "listing",
+            "language",
+            NamedArg {
+                pattern: Pattern::Text,
+                required: false,
+                default: None,
+            }
+        );
+
+        // Elements that can be the target of an \xref.
+        for tag in ["book", "article", "part", "chapter", "section", "subsection", "simplesect",
+                    "listing", "screen", "ul", "ol", "procedure", "namedlist"].iter() {
+            schema.add_named_arg(
+                tag,
+                "id",
+                NamedArg {
+                    pattern: id_string.clone(),
+                    required: false,
+                    default: None,
+                }
+            );
+        }
+
         schema
     };
 }
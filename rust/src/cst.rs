@@ -0,0 +1,181 @@
+use crate::{ast::Tag, parser};
+use nom::{
+    branch::alt,
+    bytes::complete::tag,
+    character::complete::{char, none_of, one_of},
+    combinator::{all_consuming, map, recognize},
+    multi::{many0, many1, many_m_n},
+    sequence::tuple,
+    IResult,
+};
+use std::fmt;
+
+/// A lossless concrete syntax tree of an SST document. Unlike
+/// `ast::Doc`, it preserves raw blocks, the `\begin`/`\end` form of
+/// elements and all whitespace, so that printing it yields exactly
+/// the source it was parsed from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cst(pub Vec<Node>);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Node {
+    /// Plain text, including whitespace and escape sequences (which
+    /// are kept in their source form).
+    Text(String),
+    /// A raw block, without the delimiters, and the number of `#`
+    /// characters in its fence.
+    Raw(String, usize),
+    Element(Element),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Element {
+    pub tag: Tag,
+    /// Whether the element is written as `\begin{tag}...\end{tag}`.
+    /// If so, the last positional argument is the text between the
+    /// tags.
+    pub long: bool,
+    /// The named arguments in source order.
+    pub named_args: Vec<(String, Cst)>,
+    pub pos_args: Vec<Cst>,
+}
+
+impl fmt::Display for Cst {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for node in &self.0 {
+            write!(f, "{}", node)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Node {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Node::Text(text) => write!(f, "{}", text),
+            Node::Raw(text, fence) => {
+                let fence = "#".repeat(*fence);
+                write!(f, "{{{{{}{}{}}}}}", fence, text, fence)
+            }
+            Node::Element(element) => write!(f, "{}", element),
+        }
+    }
+}
+
+impl fmt::Display for Element {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut pos_args = &self.pos_args[..];
+        if self.long {
+            write!(f, "\\begin{{{}}}", self.tag)?;
+            pos_args = &pos_args[..pos_args.len() - 1];
+        } else {
+            write!(f, "\\{}", self.tag)?;
+        }
+        for (name, arg) in &self.named_args {
+            write!(f, "[{}={}]", name, arg)?;
+        }
+        for arg in pos_args {
+            write!(f, "{{{}}}", arg)?;
+        }
+        if self.long {
+            write!(f, "{}\\end{{{}}}", self.pos_args.last().unwrap(), self.tag)?;
+        }
+        Ok(())
+    }
+}
+
+/* The grammar below must accept the same language as `parser`. It
+doesn't produce error messages: callers are expected to check the
+input with `parser::parse_string` first. */
+
+fn text(input: &str) -> IResult<&str, Node> {
+    map(
+        many1(alt((
+            recognize(none_of("{}[]\\")),
+            recognize(tuple((char('\\'), one_of("\\{}[]")))),
+            recognize(tuple((
+                tag("\\u{"),
+                many_m_n(1, 6, one_of("0123456789abcdefABCDEF")),
+                char('}'),
+            ))),
+        ))),
+        |chunks| Node::Text(chunks.concat()),
+    )(input)
+}
+
+fn raw(input: &str) -> IResult<&str, Node> {
+    let (rest, _) = tag("{{")(input)?;
+    let (fence, text, len) =
+        parser::scan_raw(rest).ok_or(nom::Err::Error((input, nom::error::ErrorKind::Tag)))?;
+    Ok((&rest[len..], Node::Raw(text.to_string(), fence)))
+}
+
+fn tag_name(input: &str) -> IResult<&str, String> {
+    map(
+        many1(one_of("abcdefghijklmnopqrstuvwxyz0123456789#")),
+        |cs| cs.into_iter().collect(),
+    )(input)
+}
+
+fn named_arg(input: &str) -> IResult<&str, (String, Cst)> {
+    map(
+        tuple((char('['), tag_name, char('='), cst, char(']'))),
+        |(_, name, _, arg, _)| (name, arg),
+    )(input)
+}
+
+fn pos_arg(input: &str) -> IResult<&str, Cst> {
+    map(tuple((char('{'), cst, char('}'))), |(_, arg, _)| arg)(input)
+}
+
+fn element(input: &str) -> IResult<&str, Node> {
+    let (rest, (_, tag, named_args, pos_args)) =
+        tuple((char('\\'), tag_name, many0(named_arg), many1(pos_arg)))(input)?;
+    if tag == "begin" || tag == "end" {
+        Err(nom::Err::Error((input, nom::error::ErrorKind::Tag)))
+    } else {
+        Ok((
+            rest,
+            Node::Element(Element {
+                tag,
+                long: false,
+                named_args,
+                pos_args,
+            }),
+        ))
+    }
+}
+
+fn long_element(input: &str) -> IResult<&str, Node> {
+    let (rest, (_, tag, _, named_args, mut pos_args, body, _, _, _)) = tuple((
+        tag("\\begin{"),
+        tag_name,
+        char('}'),
+        many0(named_arg),
+        many0(pos_arg),
+        cst,
+        tag("\\end{"),
+        tag_name,
+        char('}'),
+    ))(input)?;
+    pos_args.push(body);
+    Ok((
+        rest,
+        Node::Element(Element {
+            tag,
+            long: true,
+            named_args,
+            pos_args,
+        }),
+    ))
+}
+
+fn cst(input: &str) -> IResult<&str, Cst> {
+    map(many0(alt((text, raw, element, long_element))), Cst)(input)
+}
+
+/// Parse a string that is known to be syntactically valid into a
+/// concrete syntax tree.
+pub fn parse_string(s: &str) -> Option<Cst> {
+    all_consuming(cst)(s).ok().map(|(_, cst)| cst)
+}
@@ -0,0 +1,232 @@
+use crate::{ast::Pos, eval, parser, schema, schema_check, validate, xref};
+use std::fs;
+use std::path::Path;
+
+/// An error message together with the source locations it refers
+/// to, rendered in the style of rustc.
+pub struct Diagnostic {
+    pub message: String,
+    pub pos: Option<Pos>,
+    pub notes: Vec<Note>,
+}
+
+/// A secondary message attached to a diagnostic, optionally pointing
+/// at another location (e.g. where an unmatched tag was opened).
+pub struct Note {
+    pub message: String,
+    pub pos: Option<Pos>,
+}
+
+impl Diagnostic {
+    pub fn new(message: String, pos: Option<Pos>) -> Self {
+        Diagnostic {
+            message,
+            pos,
+            notes: vec![],
+        }
+    }
+
+    pub fn note(mut self, message: String, pos: Option<Pos>) -> Self {
+        self.notes.push(Note { message, pos });
+        self
+    }
+
+    /// Render the diagnostic, showing the source line and a caret
+    /// for every location. Positions without a filename refer to
+    /// `filename`, whose contents are `source` if given. Other files
+    /// are read from disk.
+    pub fn render(&self, filename: &Path, source: Option<&str>) -> String {
+        let mut out = format!("error: {}\n", self.message);
+        if let Some(pos) = &self.pos {
+            render_snippet(pos, filename, source, &mut out);
+        }
+        for note in &self.notes {
+            match &note.pos {
+                Some(pos) => {
+                    out.push_str(&format!("note: {}\n", note.message));
+                    render_snippet(pos, filename, source, &mut out);
+                }
+                None => out.push_str(&format!("  = note: {}\n", note.message)),
+            }
+        }
+        out
+    }
+}
+
+fn render_snippet(pos: &Pos, main_filename: &Path, main_source: Option<&str>, out: &mut String) {
+    let filename = match &pos.filename {
+        Some(filename) => &***filename,
+        None => main_filename,
+    };
+
+    let line_nr = (pos.line + 1).to_string();
+    let gutter = " ".repeat(line_nr.len());
+
+    out.push_str(&format!(
+        "{}--> {}:{}:{}\n",
+        gutter,
+        filename.display(),
+        pos.line + 1,
+        pos.column + 1
+    ));
+
+    let read_source;
+    let source = match main_source {
+        Some(source) if filename == main_filename => source,
+        _ => match fs::read_to_string(filename) {
+            Ok(source) => {
+                read_source = source;
+                &read_source
+            }
+            Err(_) => return,
+        },
+    };
+
+    if let Some(line) = source.lines().nth(pos.line as usize) {
+        /* Keep tabs in the caret line so that the caret lines up
+        with the source line. */
+        let indent: String = line
+            .chars()
+            .take(pos.column as usize)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        out.push_str(&format!("{} |\n", gutter));
+        out.push_str(&format!("{} | {}\n", line_nr, line));
+        out.push_str(&format!("{} | {}^\n", gutter, indent));
+    }
+}
+
+impl From<&parser::Error> for Diagnostic {
+    fn from(err: &parser::Error) -> Self {
+        let diag = Diagnostic::new(err.to_string(), Some(err.pos().clone()));
+        match err {
+            parser::Error::MismatchingTags(open_tag, _, open_pos, _)
+            | parser::Error::MissingEnd(open_tag, open_pos, _) => diag.note(
+                format!("\\begin{{{}}} is here", open_tag),
+                Some(open_pos.clone()),
+            ),
+            _ => diag,
+        }
+    }
+}
+
+impl From<&eval::Error> for Diagnostic {
+    fn from(err: &eval::Error) -> Self {
+        /* Report the innermost error, and the includes that lead to
+        it as notes. */
+        let mut includes = vec![];
+        let mut err = err;
+        while let eval::Error::InInclude(pos, inner) = err {
+            includes.push(pos);
+            err = inner;
+        }
+        let mut diag = match err {
+            eval::Error::Syntax(errors) if !errors.is_empty() => {
+                let mut diag = Diagnostic::from(&errors[0]);
+                for err in &errors[1..] {
+                    diag = diag.note(format!("also: {}", err), Some(err.pos().clone()));
+                }
+                diag
+            }
+            eval::Error::WrongMacroArgCount(_, def_pos, _, _, _) => {
+                let diag = Diagnostic::new(err.to_string(), err.pos().cloned());
+                match def_pos {
+                    Some(def_pos) => {
+                        diag.note("macro is defined here".to_string(), Some(def_pos.clone()))
+                    }
+                    None => diag.note("defined on the command line with -D".to_string(), None),
+                }
+            }
+            _ => Diagnostic::new(err.to_string(), err.pos().cloned()),
+        };
+        for pos in includes.into_iter().rev() {
+            diag = diag.note("included from here".to_string(), Some(pos.clone()));
+        }
+        diag
+    }
+}
+
+impl From<&schema::Error> for Diagnostic {
+    fn from(err: &schema::Error) -> Self {
+        Diagnostic::new(err.to_string(), err.pos())
+    }
+}
+
+impl From<&schema_check::Error> for Diagnostic {
+    fn from(err: &schema_check::Error) -> Self {
+        Diagnostic::new(err.to_string(), None)
+    }
+}
+
+impl From<&validate::Error> for Diagnostic {
+    fn from(err: &validate::Error) -> Self {
+        /* Report the innermost error, and the elements it occurs in
+        as notes. */
+        let mut outer = vec![];
+        let mut err = err;
+        while let validate::Error::WrongElementContent(tag, pos, inner) = err {
+            outer.push((tag, pos));
+            err = inner;
+        }
+        let mut diag = Diagnostic::new(err.to_string(), err.pos().cloned());
+        for (tag, pos) in outer.into_iter().rev() {
+            diag = diag.note(format!("in the contents of \\{}", tag), Some(pos.clone()));
+        }
+        diag
+    }
+}
+
+impl From<&xref::Error> for Diagnostic {
+    fn from(err: &xref::Error) -> Self {
+        let diag = Diagnostic::new(err.to_string(), Some(err.pos().clone()));
+        match err {
+            xref::Error::DuplicateId(_, _, first_pos) => {
+                diag.note("first defined here".to_string(), Some(first_pos.clone()))
+            }
+            _ => diag,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pos(line: u32, column: u32) -> Option<Pos> {
+        Some(Pos {
+            filename: None,
+            line,
+            column,
+            offset: 0,
+        })
+    }
+
+    #[test]
+    fn render() {
+        let filename = Path::new(env!("CARGO_MANIFEST_DIR")).join("../test/diagnostic.sst");
+        let diag = Diagnostic::new("unexpected \\emph".to_string(), pos(0, 6))
+            .note("in a tab-indented line".to_string(), pos(1, 5))
+            .note("without a position".to_string(), None);
+        assert_eq!(
+            diag.render(&filename, None),
+            format!(
+                "error: unexpected \\emph
+ --> {0}:1:7
+  |
+1 | Héllo \\emph{{wörld}}
+  |       ^
+note: in a tab-indented line
+ --> {0}:2:6
+  |
+2 | \tTab \\bad{{x}}
+  | \t    ^
+  = note: without a position
+",
+                filename.display()
+            )
+        );
+        assert!(diag
+            .render(Path::new("-"), Some("Hello \\emph{stdin}\n"))
+            .contains("1 | Hello \\emph{stdin}\n  |       ^\n"));
+    }
+}
@@ -1,5 +1,7 @@
 use crate::{ast::*, parser};
 use std::collections::HashMap;
+use std::error;
+use std::fmt;
 use std::fs;
 use std::io;
 use std::path::{Path, PathBuf};
@@ -8,18 +10,111 @@ use std::sync::Arc;
 
 #[derive(Debug)]
 pub enum Error {
-    WrongMacroArgCount(String, usize, usize), // FIXME: add Pos
-    WrongDefArgCount(usize),
-    InvalidMacroName,
-    BadArity,
+    /// A macro call with the wrong number of arguments, with the
+    /// position of the call and of the macro definition (if it was
+    /// defined in a document).
+    WrongMacroArgCount(Pos, Option<Pos>, String, usize, usize),
+    WrongDefArgCount(Pos, usize),
+    InvalidMacroName(Pos),
+    BadArity(Pos),
     BadStrip(Pos),
     BadInclude(Pos),
-    UnknownBase(Pos),
+    BadIf(Pos),
+    BadIfdef(Pos),
     IOError(Pos, PathBuf, io::Error),
+    /// Syntax errors in an included file.
+    Syntax(Vec<parser::Error>),
+    /// An error in a file included at the given position.
+    InInclude(Pos, Box<Error>),
+    /// A file that (indirectly) includes itself.
+    IncludeCycle(Pos, Vec<PathBuf>),
 }
 
-pub fn eval(doc: &Doc) -> Result<Doc, Error> {
-    eval2(None, doc)
+impl Error {
+    pub fn pos(&self) -> Option<&Pos> {
+        match self {
+            Error::WrongMacroArgCount(pos, _, _, _, _) => Some(pos),
+            Error::WrongDefArgCount(pos, _) => Some(pos),
+            Error::InvalidMacroName(pos) => Some(pos),
+            Error::BadArity(pos) => Some(pos),
+            Error::BadStrip(pos) => Some(pos),
+            Error::BadInclude(pos) => Some(pos),
+            Error::BadIf(pos) => Some(pos),
+            Error::BadIfdef(pos) => Some(pos),
+            Error::IOError(pos, _, _) => Some(pos),
+            Error::Syntax(errors) => errors.first().map(parser::Error::pos),
+            Error::InInclude(_, err) => err.pos(),
+            Error::IncludeCycle(pos, _) => Some(pos),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::WrongMacroArgCount(_, _, name, expected, got) => write!(
+                f,
+                "macro \\{} expects {} arguments, but got {}",
+                name, expected, got
+            ),
+            Error::WrongDefArgCount(_, got) => {
+                write!(f, "\\def expects 2 arguments, but got {}", got)
+            }
+            Error::InvalidMacroName(_) => write!(f, "invalid macro name"),
+            Error::BadArity(_) => write!(f, "macro arity must be a number"),
+            Error::BadStrip(_) => write!(f, "\\strip expects 1 argument"),
+            Error::BadInclude(_) => write!(f, "\\include expects a file name"),
+            Error::BadIf(_) => write!(f, "\\if expects a condition and 1 or 2 arguments"),
+            Error::BadIfdef(_) => write!(f, "\\ifdef expects a name and 1 or 2 arguments"),
+            Error::IOError(_, path, err) => {
+                write!(f, "cannot read '{}': {}", path.display(), err)
+            }
+            Error::Syntax(errors) => match errors.first() {
+                Some(err) => write!(f, "{}", err),
+                None => write!(f, "syntax error"),
+            },
+            Error::InInclude(_, err) => write!(f, "{}", err),
+            Error::IncludeCycle(_, cycle) => {
+                let cycle: Vec<_> = cycle.iter().map(|p| p.display().to_string()).collect();
+                write!(f, "include cycle: {}", cycle.join(" -> "))
+            }
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::IOError(_, _, err) => Some(err),
+            Error::InInclude(_, err) => Some(&**err),
+            _ => None,
+        }
+    }
+}
+
+/// Evaluate macros, includes and conditionals in `doc`. Relative
+/// includes that are not found next to the including file are looked
+/// up in the directories in `include_path`. `defines` are variables
+/// that can be tested by `\if` and `\ifdef` in every file.
+pub fn eval(
+    doc: &Doc,
+    include_path: &[PathBuf],
+    defines: &[(String, String)],
+) -> Result<Doc, Error> {
+    let ctx = Context {
+        include_path,
+        vars: defines.iter().cloned().collect(),
+    };
+    eval2(None, &ctx, &[], doc)
+}
+
+/// Settings that apply to the whole evaluation, including included
+/// files.
+struct Context<'a> {
+    include_path: &'a [PathBuf],
+    /// Variables defined on the command line. These are separate
+    /// from macros, so they never replace a macro or element.
+    vars: HashMap<String, String>,
 }
 
 type Env = Option<Rc<Macro>>;
@@ -30,38 +125,54 @@ struct Macro {
     arity: usize,
     defaults: HashMap<String, Doc>,
     body: Doc,
+    /// Where the macro was defined, if it was defined in a document.
+    pos: Option<Pos>,
     next: Env,
 }
 
-fn eval2(env: Env, doc: &Doc) -> Result<Doc, Error> {
+/// `includes` is the stack of `\include` elements through which
+/// `doc` was reached, outermost first.
+fn eval2(env: Env, ctx: &Context, includes: &[Pos], doc: &Doc) -> Result<Doc, Error> {
     let mut items = vec![];
-    eval_into(&mut items, env, doc)?;
+    eval_into(&mut items, env, ctx, includes, doc)?;
     Ok(Doc(items))
 }
 
-fn eval_into(items: &mut Vec<Item>, mut env: Env, doc: &Doc) -> Result<(), Error> {
+fn eval_into(
+    items: &mut Vec<Item>,
+    mut env: Env,
+    ctx: &Context,
+    includes: &[Pos],
+    doc: &Doc,
+) -> Result<(), Error> {
     for item in doc.iter() {
         match item {
-            Item::Text { text, pos } => append_text(items, text, pos),
+            Item::Text { text, pos, end } => append_text(items, text, pos, end),
             Item::Element(elem) => {
                 if elem.tag == "def" {
                     if elem.pos_args.len() != 2 {
-                        return Err(Error::WrongDefArgCount(elem.pos_args.len()));
+                        return Err(Error::WrongDefArgCount(
+                            elem.pos.clone(),
+                            elem.pos_args.len(),
+                        ));
                     }
+                    let bad_arity = || Error::BadArity(elem.pos.clone());
                     env = Some(Rc::new(Macro {
-                        name: elem.pos_args[0][0]
-                            .get_text()
-                            .ok_or(Error::InvalidMacroName)?
+                        name: elem.pos_args[0]
+                            .first()
+                            .and_then(Item::get_text)
+                            .ok_or_else(|| Error::InvalidMacroName(elem.pos.clone()))?
                             .to_string(),
                         arity: match elem.named_args.get("arity") {
                             None => 0,
                             Some(x) => get_text(&x)
-                                .ok_or(Error::BadArity)?
+                                .ok_or_else(bad_arity)?
                                 .parse()
-                                .map_err(|_| Error::BadArity)?,
+                                .map_err(|_| bad_arity())?,
                         },
                         defaults: elem.named_args.clone(),
                         body: elem.pos_args[1].clone(),
+                        pos: Some(elem.pos.clone()),
                         next: env.clone(),
                     }));
                 } else if elem.tag == "#" {
@@ -69,28 +180,69 @@ fn eval_into(items: &mut Vec<Item>, mut env: Env, doc: &Doc) -> Result<(), Error
                     if elem.pos_args.len() != 1 {
                         return Err(Error::BadStrip(elem.pos.clone()));
                     }
-                    eval_into(items, env.clone(), &elem.pos_args[0])?;
+                    eval_into(items, env.clone(), ctx, includes, &elem.pos_args[0])?;
+                } else if elem.tag == "if" || elem.tag == "ifdef" {
+                    /* Evaluate only the selected branch, so that the
+                    other branch is never validated. */
+                    let (cond, branches) = if elem.tag == "if" {
+                        (
+                            eval_condition(elem, &env, ctx, includes)?,
+                            &elem.pos_args[..],
+                        )
+                    } else {
+                        let name = elem
+                            .pos_args
+                            .first()
+                            .and_then(|arg| get_text(arg))
+                            .ok_or_else(|| Error::BadIfdef(elem.pos.clone()))?;
+                        let name = name.trim();
+                        (
+                            ctx.vars.contains_key(name) || lookup_env(name, &env).is_some(),
+                            &elem.pos_args[1..],
+                        )
+                    };
+                    let no_cond = elem.tag == "if" && elem.named_args.is_empty();
+                    if no_cond || branches.is_empty() || branches.len() > 2 {
+                        return Err(if elem.tag == "if" {
+                            Error::BadIf(elem.pos.clone())
+                        } else {
+                            Error::BadIfdef(elem.pos.clone())
+                        });
+                    }
+                    let branch = if cond {
+                        branches.first()
+                    } else {
+                        branches.get(1)
+                    };
+                    if let Some(branch) = branch {
+                        eval_into(items, env.clone(), ctx, includes, branch)?;
+                    }
                 } else if elem.tag == "include" {
-                    let (filename, file) = read_file_from(&elem)?;
-                    let ast = parser::parse_string(Some(&filename), &file).expect("Parse error");
-                    eval_into(items, None, &ast)?;
+                    let (filename, file) = read_file_from(&elem, ctx.include_path)?;
+                    let mut includes = includes.to_vec();
+                    includes.push(elem.pos.clone());
+                    check_include_cycle(&includes, &filename)?;
+                    parser::parse_string(Some(&filename), &file)
+                        .map_err(Error::Syntax)
+                        .and_then(|ast| eval_into(items, None, ctx, &includes, &ast))
+                        .map_err(|err| Error::InInclude(elem.pos.clone(), Box::new(err)))?;
                 } else if elem.tag == "includeraw" {
-                    let (filename, file) = read_file_from(&elem)?;
-                    append_text(
-                        items,
-                        &file,
-                        &Pos {
-                            filename: Some(Arc::new(filename)),
-                            line: 0,
-                            column: 0,
-                        },
-                    );
+                    let (filename, file) = read_file_from(&elem, ctx.include_path)?;
+                    let pos = Pos {
+                        filename: Some(Arc::new(filename)),
+                        line: 0,
+                        column: 0,
+                        offset: 0,
+                    };
+                    append_text(items, &file, &pos, &pos.advance(&file));
                 } else {
                     if let Some(m) = lookup_env(&elem.tag, &env) {
                         let mut env = m.next.clone();
 
                         if m.arity != elem.pos_args.len() && !(m.arity == 0 && elem.is_empty()) {
                             return Err(Error::WrongMacroArgCount(
+                                elem.pos.clone(),
+                                m.pos.clone(),
                                 m.name.clone(),
                                 m.arity,
                                 elem.pos_args.len(),
@@ -99,30 +251,38 @@ fn eval_into(items: &mut Vec<Item>, mut env: Env, doc: &Doc) -> Result<(), Error
 
                         for (name, def) in &m.defaults {
                             match elem.named_args.get(name) {
-                                None => env = to_macro(name.to_string(), &def, &env),
-                                Some(arg) => env = to_macro(name.to_string(), &arg, &env),
+                                None => {
+                                    env = to_macro(name.to_string(), &def, Some(&elem.pos), &env)
+                                }
+                                Some(arg) => {
+                                    env = to_macro(name.to_string(), &arg, Some(&elem.pos), &env)
+                                }
                             }
                         }
 
                         for n in 0..m.arity {
-                            env = to_macro(n.to_string(), &elem.pos_args[n], &env);
+                            env = to_macro(n.to_string(), &elem.pos_args[n], Some(&elem.pos), &env);
                         }
 
-                        eval_into(items, env.clone(), &m.body)?;
+                        eval_into(items, env.clone(), ctx, includes, &m.body)?;
                     } else {
                         let mut named_args = HashMap::new();
                         for (name, body) in &elem.named_args {
-                            named_args.insert(name.clone(), eval2(env.clone(), &body)?);
+                            named_args
+                                .insert(name.clone(), eval2(env.clone(), ctx, includes, &body)?);
                         }
                         let mut pos_args = vec![];
                         for arg in &elem.pos_args {
-                            pos_args.push(eval2(env.clone(), &arg)?);
+                            pos_args.push(eval2(env.clone(), ctx, includes, &arg)?);
                         }
                         items.push(Item::Element(Element {
                             tag: elem.tag.clone(),
                             named_args,
                             pos_args,
+                            named_arg_ranges: elem.named_arg_ranges.clone(),
+                            pos_arg_ranges: elem.pos_arg_ranges.clone(),
                             pos: elem.pos.clone(),
+                            end: elem.end.clone(),
                         }));
                     }
                 }
@@ -133,12 +293,45 @@ fn eval_into(items: &mut Vec<Item>, mut env: Env, doc: &Doc) -> Result<(), Error
     Ok(())
 }
 
-fn append_text(items: &mut Vec<Item>, s2: &str, p2: &Pos) {
-    if let Some(Item::Text { text, .. }) = items.last_mut() {
+/// Return whether every named argument `name=value` of an `\if`
+/// element matches the variable `name`, which is either defined on
+/// the command line or by a `\def` in the document. The value can be
+/// a semicolon-separated list of alternatives.
+fn eval_condition(
+    elem: &Element,
+    env: &Env,
+    ctx: &Context,
+    includes: &[Pos],
+) -> Result<bool, Error> {
+    for (name, value) in &elem.named_args {
+        let actual = match (ctx.vars.get(name), lookup_env(name, env)) {
+            (Some(value), _) => value.clone(),
+            (None, Some(m)) => eval_text(m.next.clone(), ctx, includes, &m.body)?,
+            (None, None) => return Ok(false),
+        };
+        let value = eval_text(env.clone(), ctx, includes, value)?;
+        if !value.split(';').any(|v| v.trim() == actual.trim()) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Evaluate `doc` and return its text, ignoring any elements.
+fn eval_text(env: Env, ctx: &Context, includes: &[Pos], doc: &Doc) -> Result<String, Error> {
+    Ok(eval2(env, ctx, includes, doc)?
+        .iter()
+        .filter_map(Item::get_text)
+        .collect())
+}
+
+fn append_text(items: &mut Vec<Item>, s2: &str, p2: &Pos, e2: &Pos) {
+    if let Some(Item::Text { text, end, .. }) = items.last_mut() {
         text.push_str(s2);
+        *end = e2.clone();
         return;
     }
-    items.push(Item::new_text(s2.to_string(), p2.clone()))
+    items.push(Item::new_text(s2.to_string(), p2.clone(), e2.clone()))
 }
 
 fn lookup_env(name: &str, mut env: &Env) -> Env {
@@ -150,12 +343,13 @@ fn lookup_env(name: &str, mut env: &Env) -> Env {
     }
 }
 
-fn to_macro(name: String, body: &Doc, env: &Env) -> Env {
+fn to_macro(name: String, body: &Doc, pos: Option<&Pos>, env: &Env) -> Env {
     Some(Rc::new(Macro {
         name,
         arity: 0,
         defaults: HashMap::new(),
         body: body.clone(),
+        pos: pos.cloned(),
         next: env.clone(), // FIXME?
     }))
 }
@@ -171,23 +365,159 @@ fn get_text<'a>(arg: &'a Doc) -> Option<&'a str> {
     }
 }
 
-fn read_file_from(elem: &Element) -> Result<(PathBuf, String), Error> {
+/// Read the file named by an `\include` or `\includeraw` element.
+/// A relative name is resolved against the directory of the including
+/// file (or the current directory if it has no file name), and then
+/// against the directories in `include_path`.
+fn read_file_from(elem: &Element, include_path: &[PathBuf]) -> Result<(PathBuf, String), Error> {
     if elem.pos_args.len() != 1 {
         return Err(Error::BadInclude(elem.pos.clone()));
     }
     let filename =
         get_text(&elem.pos_args[0]).ok_or_else(|| Error::BadInclude(elem.pos.clone()))?;
-    if let Some(parent_filename) = &elem.pos.filename {
-        let path = Path::new(&**parent_filename)
+    let base = match &elem.pos.filename {
+        Some(parent_filename) => Path::new(&**parent_filename)
             .parent()
             .unwrap()
-            .join(&filename);
-        match fs::read_to_string(&path) {
-            Ok(s) => Ok((path, s)),
-            Err(err) => Err(Error::IOError(elem.pos.clone(), path, err)),
+            .to_path_buf(),
+        None => PathBuf::new(),
+    };
+    let path = std::iter::once(&base)
+        .chain(include_path)
+        .map(|dir| dir.join(filename))
+        .find(|path| path.is_file())
+        .unwrap_or_else(|| base.join(filename));
+    match fs::read_to_string(&path) {
+        Ok(s) => Ok((path, s)),
+        Err(err) => Err(Error::IOError(elem.pos.clone(), path, err)),
+    }
+}
+
+/// Return an error if `filename` is already being evaluated, i.e. if
+/// it is the file containing one of the `\include` elements in
+/// `includes`.
+fn check_include_cycle(includes: &[Pos], filename: &Path) -> Result<(), Error> {
+    let canonical = |path: &Path| fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let target = canonical(filename);
+    let files: Vec<PathBuf> = includes
+        .iter()
+        .filter_map(|pos| pos.filename.as_ref().map(|f| canonical(f)))
+        .collect();
+    if let Some(start) = files.iter().position(|f| *f == target) {
+        let mut cycle = files[start..].to_vec();
+        cycle.push(target);
+        return Err(Error::IncludeCycle(includes.last().unwrap().clone(), cycle));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn include_parse_error() {
+        let filename = Path::new(env!("CARGO_MANIFEST_DIR")).join("../test/main.sst");
+        let doc =
+            parser::parse_string(Some(&filename), "Text.\n\\include{element-eof.sst}").unwrap();
+        match eval(&doc, &[], &[]) {
+            Err(Error::InInclude(pos, err)) => {
+                assert_eq!(pos.line, 1);
+                match *err {
+                    Error::Syntax(errors) => assert_eq!(errors.len(), 1),
+                    err => panic!("unexpected error: {:?}", err),
+                }
+            }
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn include_cycle() {
+        let filename = Path::new(env!("CARGO_MANIFEST_DIR")).join("../test/include-cycle-a.sst");
+        let doc =
+            parser::parse_string(Some(&filename), &fs::read_to_string(&filename).unwrap()).unwrap();
+        let mut err = eval(&doc, &[], &[]).unwrap_err();
+        let mut depth = 0;
+        while let Error::InInclude(_, inner) = err {
+            err = *inner;
+            depth += 1;
+        }
+        assert_eq!(depth, 1);
+        match err {
+            Error::IncludeCycle(_, cycle) => {
+                let names: Vec<_> = cycle.iter().map(|p| p.file_name().unwrap()).collect();
+                assert_eq!(
+                    names,
+                    [
+                        "include-cycle-a.sst",
+                        "include-cycle-b.sst",
+                        "include-cycle-a.sst"
+                    ]
+                );
+            }
+            err => panic!("unexpected error: {:?}", err),
+        }
+    }
+
+    #[test]
+    fn include_path() {
+        let test_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../test");
+        let first_text = |doc: Doc| match &doc[0] {
+            Item::Text { text, .. } => text.clone(),
+            item => panic!("unexpected item: {:?}", item),
+        };
+        let doc = parser::parse_string(None, "\\include{element.sst}").unwrap();
+        assert!(eval(&doc, &[], &[]).is_err());
+        let res = eval(&doc, std::slice::from_ref(&test_dir), &[]).unwrap();
+        assert_eq!(first_text(res), "Hello ");
+        let sst = format!("\\include{{{}}}", test_dir.join("element.sst").display());
+        let doc = parser::parse_string(None, &sst).unwrap();
+        assert_eq!(first_text(eval(&doc, &[], &[]).unwrap()), "Hello ");
+    }
+
+    #[test]
+    fn macro_arg_count() {
+        let doc = parser::parse_string(None, "\\def[arity=1]{m}{x}\n\n\\m{a}{b}").unwrap();
+        match eval(&doc, &[], &[]) {
+            Err(Error::WrongMacroArgCount(call, Some(def), name, 1, 2)) => {
+                assert_eq!((call.line, def.line), (2, 0));
+                assert_eq!(name, "m");
+            }
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn conditionals() {
+        let eval_with = |sst: &str, defines: &[(&str, &str)]| {
+            let doc = parser::parse_string(None, sst).unwrap();
+            let defines: Vec<_> = defines
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect();
+            let res = eval(&doc, &[], &defines).unwrap();
+            res.iter().filter_map(Item::get_text).collect::<String>()
+        };
+        let sst =
+            "\\ifdef{os}{has os}{no os}\n\\if[os=linux;freebsd]{unix}{other}\n\\if[os=macos]{mac}";
+        assert_eq!(eval_with(sst, &[]), "no os\nother\n");
+        assert_eq!(eval_with(sst, &[("os", "linux")]), "has os\nunix\n");
+        assert_eq!(eval_with(sst, &[("os", "macos")]), "has os\nother\nmac");
+        assert_eq!(eval_with("\\def{os}{linux}\\if[os=linux]{yes}", &[]), "yes");
+
+        /* Variables don't replace elements. */
+        let doc = parser::parse_string(None, "\\code{x}").unwrap();
+        let res = eval(&doc, &[], &[("code".to_string(), "1".to_string())]).unwrap();
+        assert!(match &res[0] {
+            Item::Element(elem) => elem.tag == "code",
+            _ => false,
+        });
+
+        let doc = parser::parse_string(None, "\\if{yes}{no}").unwrap();
+        match eval(&doc, &[], &[]) {
+            Err(Error::BadIf(_)) => {}
+            res => panic!("unexpected result: {:?}", res),
         }
-    } else {
-        // FIXME: we don't need parent_filename if filename is absolute.
-        Err(Error::UnknownBase(elem.pos.clone()))
     }
 }
@@ -0,0 +1,481 @@
+use crate::{
+    ast::{Doc, Item, Pos},
+    core,
+    cst::*,
+    parser,
+    schema::Pattern,
+};
+
+/// Elements whose content is whitespace-sensitive. They are never
+/// rewrapped, and only re-indented if that doesn't change their
+/// content.
+pub const VERBATIM: &[&str] = &["listing", "screen", "#"];
+
+/// Format a document: put multi-line block-level element arguments
+/// on their own lines, indent them by two spaces per level of
+/// nesting, and rewrap paragraphs to `width` columns. Raw blocks are
+/// never changed.
+pub fn format(cst: &Cst, width: usize) -> String {
+    let mut out = String::new();
+    format_block(cst, 0, 0, width, &mut out);
+    out.push('\n');
+    out
+}
+
+enum Atom<'a> {
+    /// Text without whitespace.
+    Word(&'a str),
+    /// Whitespace containing the given number of newlines.
+    Space(usize),
+    /// A raw block or element, and the indentation of the source line
+    /// on which it starts.
+    Node(&'a Node, usize),
+}
+
+#[derive(PartialEq, Eq)]
+enum Sep {
+    None,
+    Space,
+    Para,
+}
+
+/// Split the content of a document into words separated by
+/// whitespace. A word consists of text, raw blocks and elements that
+/// are not separated by whitespace.
+fn words(cst: &Cst, mut line_indent: usize) -> Vec<(Sep, Vec<Atom<'_>>)> {
+    let mut atoms = vec![];
+    for node in &cst.0 {
+        match node {
+            Node::Text(text) => {
+                let mut rest = &text[..];
+                while let Some(c) = rest.chars().next() {
+                    let is_ws = c.is_whitespace();
+                    let len = rest
+                        .find(|c: char| c.is_whitespace() != is_ws)
+                        .unwrap_or(rest.len());
+                    let (chunk, tail) = rest.split_at(len);
+                    if is_ws {
+                        let newlines = chunk.matches('\n').count();
+                        if let Some(n) = chunk.rfind('\n') {
+                            line_indent = chunk[n + 1..].chars().count();
+                        }
+                        atoms.push(Atom::Space(newlines));
+                    } else {
+                        atoms.push(Atom::Word(chunk));
+                    }
+                    rest = tail;
+                }
+            }
+            _ => atoms.push(Atom::Node(node, line_indent)),
+        }
+    }
+
+    let mut res = vec![];
+    let mut sep = Sep::None;
+    let mut word = vec![];
+    for atom in atoms {
+        match atom {
+            Atom::Space(newlines) => {
+                if !word.is_empty() {
+                    res.push((sep, word));
+                    word = vec![];
+                    sep = Sep::Space;
+                }
+                if newlines >= 2 && !res.is_empty() {
+                    sep = Sep::Para;
+                }
+            }
+            _ => word.push(atom),
+        }
+    }
+    if !word.is_empty() {
+        res.push((sep, word));
+    }
+    res
+}
+
+/// Format the content of a document or argument as a sequence of
+/// lines indented by `indent` spaces. `orig_indent` is the
+/// indentation of the source line on which the content starts.
+fn format_block(cst: &Cst, indent: usize, orig_indent: usize, width: usize, out: &mut String) {
+    let prefix = " ".repeat(indent);
+    let mut col = None;
+
+    for (sep, word) in words(cst, orig_indent) {
+        let mut inline = String::new();
+        for atom in &word {
+            match atom {
+                Atom::Word(s) => inline.push_str(s),
+                Atom::Node(node, _) => inline.push_str(&node.to_string()),
+                Atom::Space(_) => unreachable!(),
+            }
+        }
+
+        if sep == Sep::Para {
+            out.push_str("\n\n");
+            col = None;
+        }
+
+        let is_comment = word.iter().any(|atom| match atom {
+            Atom::Node(Node::Element(element), _) => element.tag == "#",
+            _ => false,
+        });
+
+        if !inline.contains('\n') && !is_comment {
+            let len = inline.chars().count();
+            match col {
+                Some(c) if c + 1 + len <= width => {
+                    out.push(' ');
+                    col = Some(c + 1 + len);
+                }
+                _ => {
+                    if col.is_some() {
+                        out.push('\n');
+                    }
+                    out.push_str(&prefix);
+                    col = Some(indent + len);
+                }
+            }
+            out.push_str(&inline);
+        } else {
+            /* Words containing comments or multi-line elements are
+            put on lines of their own. */
+            if col.is_some() {
+                out.push('\n');
+            }
+            out.push_str(&prefix);
+            for atom in &word {
+                match atom {
+                    Atom::Word(s) => out.push_str(s),
+                    Atom::Node(Node::Element(element), orig_indent) => {
+                        format_element(element, indent, *orig_indent, width, out)
+                    }
+                    Atom::Node(node, _) => out.push_str(&node.to_string()),
+                    Atom::Space(_) => unreachable!(),
+                }
+            }
+            col = Some(width);
+        }
+    }
+}
+
+fn format_element(
+    element: &Element,
+    indent: usize,
+    orig_indent: usize,
+    width: usize,
+    out: &mut String,
+) {
+    let source = element.to_string();
+
+    if !source.contains('\n') {
+        out.push_str(&source);
+        return;
+    }
+
+    if VERBATIM.contains(&&element.tag[..]) {
+        let reindented = reindent(&source, orig_indent, indent);
+        out.push_str(if same_content(&source, &reindented) {
+            &reindented
+        } else {
+            &source
+        });
+        return;
+    }
+
+    let mut pos_args = &element.pos_args[..];
+    if element.long {
+        out.push_str(&format!("\\begin{{{}}}", element.tag));
+        pos_args = &pos_args[..pos_args.len() - 1];
+    } else {
+        out.push_str(&format!("\\{}", element.tag));
+    }
+
+    for (name, arg) in &element.named_args {
+        out.push_str(&format!("[{}={}]", name, arg));
+    }
+
+    for (n, arg) in pos_args.iter().enumerate() {
+        out.push('{');
+        format_arg(
+            arg,
+            is_block_arg(&element.tag, n),
+            indent,
+            orig_indent,
+            width,
+            out,
+        );
+        out.push('}');
+    }
+
+    if element.long {
+        format_arg(
+            element.pos_args.last().unwrap(),
+            is_block_arg(&element.tag, pos_args.len()),
+            indent,
+            orig_indent,
+            width,
+            out,
+        );
+        out.push_str(&format!("\\end{{{}}}", element.tag));
+    }
+}
+
+fn format_arg(
+    arg: &Cst,
+    block: bool,
+    indent: usize,
+    orig_indent: usize,
+    width: usize,
+    out: &mut String,
+) {
+    let source = arg.to_string();
+    if !source.contains('\n') {
+        out.push_str(&source);
+    } else if !block {
+        /* Whitespace at the start and end of inline content is
+        significant, so only re-indent it. */
+        out.push_str(&reindent_cst(arg, orig_indent, indent).to_string());
+    } else if !source.trim().is_empty() {
+        out.push('\n');
+        format_block(arg, indent + 2, orig_indent, width, out);
+        out.push('\n');
+        out.push_str(&" ".repeat(indent));
+    }
+}
+
+/// Test whether positional argument `n` of the element `tag` can
+/// only contain block-level content, according to the core schema.
+fn is_block_arg(tag: &str, n: usize) -> bool {
+    match core::SCHEMA.elements.get(tag) {
+        Some(element_type) => match element_type.pos_args.get(n) {
+            Some(pattern) => !allows_inline(pattern),
+            None => false,
+        },
+        None => false,
+    }
+}
+
+fn allows_inline(pattern: &Pattern) -> bool {
+    match pattern {
+        Pattern::Element(tag) => match core::SCHEMA.patterns.get("inline") {
+            Some(Pattern::Choice(alts)) => alts.iter().any(|alt| match alt {
+                Pattern::Element(tag2) => tag == tag2,
+                _ => false,
+            }),
+            _ => true,
+        },
+        Pattern::Para(_) => false,
+        Pattern::Ref(name) => name != "block",
+        Pattern::Seq(pats) | Pattern::Choice(pats) => pats.iter().any(allows_inline),
+        Pattern::Many(_, _, pat) => allows_inline(pat),
+        _ => true,
+    }
+}
+
+/// Change the indentation of every line but the first of `s` from
+/// `from` to `to` spaces.
+fn reindent(s: &str, from: usize, to: usize) -> String {
+    let mut res = String::new();
+    for (n, line) in s.split('\n').enumerate() {
+        if n > 0 {
+            res.push('\n');
+            if !line.is_empty() {
+                let spaces = line.len() - line.trim_start_matches(' ').len();
+                res.push_str(&" ".repeat(to));
+                res.push_str(&line[spaces.min(from)..]);
+                continue;
+            }
+        }
+        res.push_str(line);
+    }
+    res
+}
+
+/// Like `reindent`, but leave the content of raw blocks unchanged.
+fn reindent_cst(cst: &Cst, from: usize, to: usize) -> Cst {
+    let mut nodes = vec![];
+    for (n, node) in cst.0.iter().enumerate() {
+        nodes.push(match node {
+            Node::Text(text) => {
+                let mut text = reindent(text, from, to);
+                /* A line that starts with a raw block or element. */
+                if text.ends_with('\n') && n + 1 < cst.0.len() {
+                    text.push_str(&" ".repeat(to));
+                }
+                Node::Text(text)
+            }
+            Node::Raw(_, _) => node.clone(),
+            Node::Element(element) => {
+                let mut element = element.clone();
+                for (_, arg) in &mut element.named_args {
+                    *arg = reindent_cst(arg, from, to);
+                }
+                for arg in &mut element.pos_args {
+                    *arg = reindent_cst(arg, from, to);
+                }
+                Node::Element(element)
+            }
+        });
+    }
+    Cst(nodes)
+}
+
+/// Test whether two pieces of source text parse to the same
+/// document, ignoring positions.
+fn same_content(s1: &str, s2: &str) -> bool {
+    match (
+        parser::parse_string(None, s1),
+        parser::parse_string(None, s2),
+    ) {
+        (Ok(doc1), Ok(doc2)) => normalize(&doc1, true, false) == normalize(&doc2, true, false),
+        _ => false,
+    }
+}
+
+/// Test whether formatting preserved the meaning of a document,
+/// i.e. whether both documents are equal except for source
+/// positions and whitespace outside of verbatim elements. Runs of
+/// whitespace are equal if they both contain a blank line (separating
+/// paragraphs) or both don't. Whitespace at the start and end of
+/// the document and of block-level arguments is ignored.
+pub fn equivalent(doc1: &Doc, doc2: &Doc) -> bool {
+    normalize(doc1, false, true) == normalize(doc2, false, true)
+}
+
+fn normalize(doc: &Doc, verbatim: bool, trim: bool) -> Doc {
+    let no_pos = Pos {
+        filename: None,
+        line: 0,
+        column: 0,
+        offset: 0,
+    };
+
+    let mut items = vec![];
+    for item in doc.iter() {
+        match item {
+            Item::Text { text, .. } => {
+                let text = if verbatim {
+                    text.clone()
+                } else {
+                    normalize_whitespace(text)
+                };
+                items.push(Item::new_text(text, no_pos.clone(), no_pos.clone()));
+            }
+            Item::Element(element) => {
+                let verbatim = verbatim || VERBATIM.contains(&&element.tag[..]);
+                let mut element = element.clone();
+                for arg in element.named_args.values_mut() {
+                    *arg = normalize(arg, verbatim, false);
+                }
+                for (n, arg) in element.pos_args.iter_mut().enumerate() {
+                    *arg = normalize(arg, verbatim, is_block_arg(&element.tag, n));
+                }
+                element.named_arg_ranges.clear();
+                element.pos_arg_ranges.clear();
+                element.pos = no_pos.clone();
+                element.end = no_pos.clone();
+                items.push(element.into());
+            }
+        }
+    }
+
+    if !verbatim && trim {
+        if let Some(Item::Text { text, .. }) = items.first_mut() {
+            *text = text.trim_start().to_string();
+        }
+        if let Some(Item::Text { text, .. }) = items.last_mut() {
+            *text = text.trim_end().to_string();
+        }
+        items.retain(|item| item.get_text() != Some(""));
+    }
+
+    Doc(items)
+}
+
+fn normalize_whitespace(s: &str) -> String {
+    let mut res = String::new();
+    let mut newlines = None;
+    for c in s.chars() {
+        if c.is_whitespace() {
+            *newlines.get_or_insert(0) += (c == '\n') as usize;
+        } else {
+            if let Some(n) = newlines.take() {
+                res.push_str(if n >= 2 { "\n\n" } else { " " });
+            }
+            res.push(c);
+        }
+    }
+    if let Some(n) = newlines {
+        res.push_str(if n >= 2 { "\n\n" } else { " " });
+    }
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn check_roundtrip(sst: &str) {
+        assert_eq!(parse_string(sst).unwrap().to_string(), sst);
+    }
+
+    #[test]
+    fn cst_roundtrip() {
+        check_roundtrip(include_str!("../../test/text.sst"));
+        check_roundtrip(include_str!("../../test/element.sst"));
+        check_roundtrip(include_str!("../../test/long-element.sst"));
+        check_roundtrip(include_str!("../../test/indent.sst"));
+        check_roundtrip(include_str!("../../test/raw.sst"));
+        check_roundtrip(include_str!("../../test/escape.sst"));
+        check_roundtrip(include_str!("../../test/fmt.sst"));
+    }
+
+    #[test]
+    fn format_doc() {
+        let sst = include_str!("../../test/fmt.sst");
+        let formatted = format(&parse_string(sst).unwrap(), 60);
+        assert_eq!(formatted, include_str!("../../test/fmt.out.sst"));
+        assert!(equivalent(
+            &parser::parse_string(None, sst).unwrap(),
+            &parser::parse_string(None, &formatted).unwrap()
+        ));
+        assert_eq!(format(&parse_string(&formatted).unwrap(), 60), formatted);
+    }
+
+    #[test]
+    fn format_inline() {
+        let sst = "Para \\emph{multi\n    line} end.\n";
+        assert_eq!(
+            format(&parse_string(sst).unwrap(), 60),
+            "Para\n\\emph{multi\n    line}\nend.\n"
+        );
+    }
+
+    #[test]
+    fn format_raw() {
+        let sst = "\\ul{\\li{\n        Some {{a\n  b}} text\n        \\emph{x {{\n  raw}}\n        y}\n}}\n";
+        let formatted = format(&parse_string(sst).unwrap(), 60);
+        assert_eq!(
+            formatted,
+            "\\ul{\n  \\li{\n    Some\n    {{a\n  b}}\n    text\n    \\emph{x {{\n  raw}}\n    y}\n  }\n}\n"
+        );
+        assert!(equivalent(
+            &parser::parse_string(None, sst).unwrap(),
+            &parser::parse_string(None, &formatted).unwrap()
+        ));
+    }
+
+    #[test]
+    fn inline_edges() {
+        let parse = |sst| parser::parse_string(None, sst).unwrap();
+        assert!(!equivalent(
+            &parse("Para \\emph{multi line} end."),
+            &parse("Para \\emph{\n  multi line\n} end.")
+        ));
+        assert!(equivalent(
+            &parse("\\ul{\\li{One}}"),
+            &parse("\\ul{\n  \\li{\n    One\n  }\n}")
+        ));
+    }
+}
@@ -1,22 +1,59 @@
 mod ast;
 mod core;
+mod cst;
+mod diagnostic;
 mod eval;
+mod format;
 mod number;
 mod parser;
 mod schema;
+mod schema_check;
 mod text_layout;
+mod to_html;
+mod to_markdown;
 mod to_text;
 mod unindent;
 mod validate;
+mod view;
+mod xref;
 
+use std::env;
 use std::fs;
-use std::io::Write;
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
+use std::process::{self, Command, Stdio};
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "sst", about = "Simple Structured Text processor")]
+struct Opts {
+    /// Add a directory to search for included files (searched
+    /// before the directories in $SST_PATH)
+    #[structopt(
+        short = "I",
+        long = "include",
+        number_of_values = 1,
+        parse(from_os_str),
+        raw(global = "true")
+    )]
+    include_path: Vec<PathBuf>,
+
+    /// Define a variable for use in \if and \ifdef
+    #[structopt(
+        short = "D",
+        long = "define",
+        value_name = "NAME[=VALUE]",
+        number_of_values = 1,
+        parse(try_from_str = "parse_define"),
+        raw(global = "true")
+    )]
+    defines: Vec<(String, String)>,
+
+    #[structopt(subcommand)]
+    command: Sst,
+}
+
+#[derive(Debug, StructOpt)]
 enum Sst {
     /// Parse an SST file
     #[structopt(name = "parse")]
@@ -38,6 +75,10 @@ enum Sst {
         /// Print validation proof in JSON
         #[structopt(short = "j", long = "json")]
         json: bool,
+        /// Schema to validate against, instead of the one declared
+        /// by the document or the built-in schema
+        #[structopt(short = "s", long = "schema")]
+        schema: Option<PathBuf>,
         /// File to validate
         input: PathBuf,
     },
@@ -45,32 +86,149 @@ enum Sst {
     /// Read an SST file in your terminal
     #[structopt(name = "read")]
     Read {
+        /// Schema to validate against, instead of the one declared
+        /// by the document or the built-in schema
+        #[structopt(short = "s", long = "schema")]
+        schema: Option<PathBuf>,
         /// File to read
         input: PathBuf,
     },
+
+    /// Reformat an SST file
+    #[structopt(name = "fmt")]
+    Fmt {
+        /// Maximum line width
+        #[structopt(short = "w", long = "width", default_value = "80")]
+        width: usize,
+        /// Overwrite the file instead of printing the result
+        #[structopt(short = "i", long = "in-place")]
+        in_place: bool,
+        /// File to reformat
+        input: PathBuf,
+    },
+
+    /// Convert an SST file to HTML
+    #[structopt(name = "html")]
+    Html {
+        /// Schema to validate against, instead of the one declared
+        /// by the document or the built-in schema
+        #[structopt(short = "s", long = "schema")]
+        schema: Option<PathBuf>,
+        /// File to convert
+        input: PathBuf,
+    },
+
+    /// Convert an SST file to Markdown
+    #[structopt(name = "markdown")]
+    Markdown {
+        /// Number chapter and section headings
+        #[structopt(short = "n", long = "numbered")]
+        numbered: bool,
+        /// Schema to validate against, instead of the one declared
+        /// by the document or the built-in schema
+        #[structopt(short = "s", long = "schema")]
+        schema: Option<PathBuf>,
+        /// File to convert
+        input: PathBuf,
+    },
+
+    /// Check a schema for undefined, unreachable and ambiguous patterns
+    #[structopt(name = "schema-check")]
+    SchemaCheck {
+        /// Schema file to check, instead of the built-in schema
+        schema: Option<PathBuf>,
+    },
+}
+
+/// Parse `NAME=VALUE`, or `NAME` to define an empty variable.
+fn parse_define(s: &str) -> Result<(String, String), String> {
+    match s.find('=') {
+        Some(0) => Err(format!("missing variable name in '{}'", s)),
+        Some(n) => Ok((s[..n].to_string(), s[n + 1..].to_string())),
+        None => Ok((s.to_string(), String::new())),
+    }
+}
+
+/// Print diagnostics for `errors` to stderr and exit with a non-zero
+/// exit code. `source` is the already-read contents of `filename`.
+fn fail<'a, E: 'a>(
+    filename: &Path,
+    source: Option<&str>,
+    errors: impl IntoIterator<Item = &'a E>,
+) -> !
+where
+    &'a E: Into<diagnostic::Diagnostic>,
+{
+    for err in errors {
+        eprint!("{}", err.into().render(filename, source));
+    }
+    process::exit(1);
+}
+
+/// Read a file, or stdin if `filename` is `-`.
+fn read_input(filename: &Path) -> String {
+    let res = if filename == Path::new("-") {
+        let mut input = String::new();
+        io::stdin().read_to_string(&mut input).map(|_| input)
+    } else {
+        fs::read_to_string(&filename)
+    };
+    res.unwrap_or_else(|err| {
+        eprintln!("error: cannot read '{}': {}", filename.display(), err);
+        process::exit(1);
+    })
 }
 
-fn parse_file(filename: &Path, include_filename: bool) -> ast::Doc {
-    let input = fs::read_to_string(&filename).expect("Unable to read file");
-    parser::parse_string(
-        if include_filename {
+/// Parse a file, returning its contents and the document.
+fn parse_file(filename: &Path, include_filename: bool) -> (String, ast::Doc) {
+    let input = read_input(filename);
+    let ast = parser::parse_string(
+        /* Documents read from stdin have no file name, so their
+        includes are resolved relative to the current directory. */
+        if include_filename && filename != Path::new("-") {
             Some(&filename)
         } else {
             None
         },
         &input,
     )
-    .expect("Parse error")
+    .unwrap_or_else(|errors| fail(filename, Some(&input), &errors));
+    (input, ast)
 }
 
-fn eval_file(filename: &Path) -> ast::Doc {
-    let ast = parse_file(filename, true);
-    eval::eval(&ast).expect("Evaluation error")
+fn eval_file(
+    filename: &Path,
+    include_path: &[PathBuf],
+    defines: &[(String, String)],
+) -> (String, ast::Doc) {
+    let (input, ast) = parse_file(filename, true);
+    let ast = eval::eval(&ast, include_path, defines)
+        .unwrap_or_else(|err| fail(filename, Some(&input), &[err]));
+    (input, ast)
 }
 
-fn validate_file(filename: &Path) -> validate::Instance {
-    let ast = eval_file(filename);
-    validate::validate(&core::SCHEMA, &ast, &filename).expect("Validation error")
+fn validate_file(
+    filename: &Path,
+    schema_file: Option<PathBuf>,
+    include_path: &[PathBuf],
+    defines: &[(String, String)],
+) -> validate::Instance {
+    let (input, mut ast) = eval_file(filename, include_path, defines);
+    let input = Some(&input[..]);
+    let declared_schema = schema::take_declaration(&mut ast);
+    let loaded_schema;
+    let schema = match schema_file.or(declared_schema) {
+        Some(schema_file) => {
+            loaded_schema =
+                schema::load(&schema_file).unwrap_or_else(|err| fail(filename, input, &[err]));
+            &loaded_schema
+        }
+        None => &*core::SCHEMA,
+    };
+    let instance = validate::validate(schema, &ast, &filename)
+        .unwrap_or_else(|errors| fail(filename, input, &errors));
+    xref::check(&ast).unwrap_or_else(|errors| fail(filename, input, &errors));
+    instance
 }
 
 fn show_in_pager(text: &str) {
@@ -97,28 +255,100 @@ fn show_in_pager(text: &str) {
 }
 
 fn main() {
-    match Sst::from_args() {
+    let opts = Opts::from_args();
+
+    let mut include_path = opts.include_path;
+    if let Some(sst_path) = env::var_os("SST_PATH") {
+        include_path.extend(env::split_paths(&sst_path));
+    }
+
+    match opts.command {
         Sst::Parse { input } => {
-            let ast = parse_file(&input, false);
+            let (_, ast) = parse_file(&input, false);
             println!("{}", &serde_json::to_string_pretty(&ast).unwrap());
         }
 
         Sst::Eval { input } => {
-            let ast = eval_file(&input);
+            let (_, ast) = eval_file(&input, &include_path, &opts.defines);
             println!("{}", &serde_json::to_string(&ast).unwrap());
         }
 
-        Sst::Check { input, json } => {
-            let instance = validate_file(&input);
+        Sst::Check {
+            input,
+            json,
+            schema,
+        } => {
+            let instance = validate_file(&input, schema, &include_path, &opts.defines);
             if json {
                 println!("{}", &serde_json::to_string(&instance).unwrap());
             }
         }
 
-        Sst::Read { input } => {
-            let instance = validate_file(&input);
+        Sst::Read { input, schema } => {
+            let instance = validate_file(&input, schema, &include_path, &opts.defines);
             let text = to_text::to_text(&instance, 80);
             show_in_pager(&text);
         }
+
+        Sst::Fmt {
+            width,
+            in_place,
+            input,
+        } => {
+            if in_place && input == Path::new("-") {
+                eprintln!("error: cannot format standard input in place");
+                process::exit(1);
+            }
+            let source = read_input(&input);
+            let ast = parser::parse_string(None, &source)
+                .unwrap_or_else(|errors| fail(&input, Some(&source), &errors));
+            let cst = cst::parse_string(&source).unwrap_or_else(|| {
+                eprintln!("error: cannot parse '{}' for formatting", input.display());
+                process::exit(1);
+            });
+            let formatted = format::format(&cst, width);
+            let new_ast = parser::parse_string(None, &formatted)
+                .unwrap_or_else(|errors| fail(&input, Some(&formatted), &errors));
+            if !format::equivalent(&ast, &new_ast) {
+                eprintln!(
+                    "error: formatting would change the meaning of '{}'",
+                    input.display()
+                );
+                process::exit(1);
+            }
+            if in_place {
+                fs::write(&input, formatted).expect("Unable to write file");
+            } else {
+                print!("{}", formatted);
+            }
+        }
+
+        Sst::Html { input, schema } => {
+            let instance = validate_file(&input, schema, &include_path, &opts.defines);
+            print!("{}", to_html::to_html(&instance));
+        }
+
+        Sst::Markdown {
+            numbered,
+            schema,
+            input,
+        } => {
+            let instance = validate_file(&input, schema, &include_path, &opts.defines);
+            print!("{}", to_markdown::to_markdown(&instance, numbered));
+        }
+
+        Sst::SchemaCheck { schema } => {
+            let filename = schema.clone().unwrap_or_else(|| PathBuf::from("core"));
+            let loaded_schema;
+            let schema = match schema {
+                Some(schema_file) => {
+                    loaded_schema = schema::load(&schema_file)
+                        .unwrap_or_else(|err| fail(&schema_file, None, &[err]));
+                    &loaded_schema
+                }
+                None => &*core::SCHEMA,
+            };
+            schema_check::check(schema).unwrap_or_else(|errors| fail(&filename, None, &errors));
+        }
     }
 }
@@ -1,4 +1,5 @@
 use crate::validate::Instance;
+use crate::view;
 use std::collections::HashMap;
 use std::hash::{Hash, Hasher};
 use std::rc::Rc;
@@ -21,12 +22,14 @@ impl<'a> PartialEq for InstanceByAddr<'a> {
 
 pub struct Numbers<'doc> {
     pub toc: HashMap<InstanceByAddr<'doc>, Rc<TocEntry<'doc>>>,
+    /// The elements that have an `id` argument.
+    pub ids: HashMap<String, &'doc Instance>,
 }
 
 pub struct TocEntry<'doc> {
     pub parent: Option<Rc<TocEntry<'doc>>>,
     pub number: usize,
-    pub title: &'doc Instance,
+    pub title: view::Content<'doc>,
 }
 
 impl<'doc> TocEntry<'doc> {
@@ -54,6 +57,7 @@ impl<'doc> Numbers<'doc> {
     pub fn create(doc: &'doc Instance) -> Self {
         let mut numbers = Numbers {
             toc: HashMap::new(),
+            ids: HashMap::new(),
         };
         let mut next_number: usize = 1;
         numbers.traverse(doc, None, &mut next_number);
@@ -67,6 +71,28 @@ impl<'doc> Numbers<'doc> {
         }
     }
 
+    /// Return the element with the given id.
+    pub fn get_target(&self, id: &str) -> Option<&'doc Instance> {
+        self.ids.get(id).copied()
+    }
+
+    /// Return the label and the title that an `\xref` to the element
+    /// with the given id should show, e.g. ("Section 2.3",
+    /// Some(title)). The label is empty for unnumbered elements that
+    /// have a title, and the title is `None` for elements that don't.
+    pub fn get_xref_label(&self, id: &str) -> Option<(String, Option<view::Content<'doc>>)> {
+        let target = view::Element::new(self.get_target(id)?)?;
+        let title = target.title();
+        let label = match (self.get_toc_entry(target.instance()), title) {
+            (Some(toc_entry), _) => {
+                format!("{} {}", element_kind(target.tag()), toc_entry.to_string())
+            }
+            (None, Some(_)) => String::new(),
+            (None, None) => element_kind(target.tag()).to_string(),
+        };
+        Some((label, title))
+    }
+
     fn traverse(
         &mut self,
         doc: &'doc Instance,
@@ -77,14 +103,16 @@ impl<'doc> Numbers<'doc> {
         let mut new_counter: usize = 1;
         let mut next_number = next_number;
 
-        match doc {
-            Instance::Element(tag, children)
-                if tag == "chapter" || tag == "section" || tag == "subsection" =>
+        match view::Element::new(doc) {
+            Some(element)
+                if element.tag() == "chapter"
+                    || element.tag() == "section"
+                    || element.tag() == "subsection" =>
             {
                 let entry = Rc::new(TocEntry {
                     parent: parent.clone(),
                     number: *next_number,
-                    title: &children[0],
+                    title: element.arg(0),
                 });
                 self.toc.insert(InstanceByAddr(doc), entry.clone());
                 parent = Some(entry);
@@ -94,14 +122,18 @@ impl<'doc> Numbers<'doc> {
             _ => {}
         }
 
+        if let Some(id) = view::Element::new(doc).and_then(|element| element.id()) {
+            self.ids.insert(id, doc);
+        }
+
         match doc {
-            Instance::Text(_) => {}
-            Instance::Element(_, children) => {
+            Instance::Text(_, _) | Instance::Value(_, _) => {}
+            Instance::Element(_, _, children, _) => {
                 for child in children.iter() {
                     self.traverse(child, parent.clone(), next_number);
                 }
             }
-            Instance::Para(child) => self.traverse(child, parent.clone(), next_number),
+            Instance::Para(child, _) => self.traverse(child, parent.clone(), next_number),
             Instance::Seq(children) => {
                 for child in children.iter() {
                     self.traverse(child, parent.clone(), next_number);
@@ -116,3 +148,19 @@ impl<'doc> Numbers<'doc> {
         }
     }
 }
+
+/// The word used to refer to an element in cross-references.
+fn element_kind(tag: &str) -> &'static str {
+    match tag {
+        "book" => "Book",
+        "article" => "Article",
+        "part" => "Part",
+        "chapter" => "Chapter",
+        "section" | "subsection" | "simplesect" => "Section",
+        "listing" => "Listing",
+        "screen" => "Screen",
+        "procedure" => "Procedure",
+        "ul" | "ol" | "namedlist" => "List",
+        _ => "Element",
+    }
+}
@@ -3,13 +3,15 @@ use nom::{
     branch::alt,
     bytes::complete::tag,
     character::complete::{char, none_of, one_of},
-    combinator::{all_consuming, cut, map},
+    combinator::{cut, map},
     error::ErrorKind,
-    multi::{many0, many1},
+    multi::{many0, many1, many_m_n},
     sequence::{preceded, tuple},
-    IResult,
+    IResult, Slice,
 };
 use nom_locate::LocatedSpanEx;
+use std::collections::HashMap;
+use std::fmt;
 use std::path::Path;
 use std::sync::Arc;
 
@@ -18,12 +20,67 @@ pub enum Error {
     UnexpectedChar(char, Pos),
     UnexpectedEOF(Pos),
     UnexpectedEnd(Pos),
-    MismatchingTags(String, String, Pos), // FIXME: record position of open tag
-    MissingEnd(String, Pos),              // FIXME: record name and position of open tag
+    /// An `\end` tag that doesn't match its `\begin` tag. Records
+    /// the names of both tags, the position of the `\begin` tag and
+    /// the position of the `\end` tag.
+    MismatchingTags(String, String, Pos, Pos),
+    /// A `\begin` tag without a matching `\end` tag. Records the name
+    /// and position of the `\begin` tag and the position where the
+    /// `\end` tag was expected.
+    MissingEnd(String, Pos, Pos),
     TagExpected(Pos),
     InvalidTagName(Pos),
+    /// A raw block without a closing `}}`, or with unbalanced braces.
+    UnterminatedRaw(Pos),
+    /// A Unicode escape that doesn't denote a valid character.
+    InvalidEscape(Pos),
 }
 
+impl Error {
+    pub fn pos(&self) -> &Pos {
+        match self {
+            Error::UnexpectedChar(_, pos) => pos,
+            Error::UnexpectedEOF(pos) => pos,
+            Error::UnexpectedEnd(pos) => pos,
+            Error::MismatchingTags(_, _, _, pos) => pos,
+            Error::MissingEnd(_, _, pos) => pos,
+            Error::TagExpected(pos) => pos,
+            Error::InvalidTagName(pos) => pos,
+            Error::UnterminatedRaw(pos) => pos,
+            Error::InvalidEscape(pos) => pos,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::UnexpectedChar(c, _) => write!(f, "unexpected character '{}'", c),
+            Error::UnexpectedEOF(_) => write!(f, "unexpected end of file"),
+            Error::UnexpectedEnd(_) => write!(f, "unexpected \\end tag"),
+            Error::MismatchingTags(open_tag, close_tag, open_pos, _) => write!(
+                f,
+                "\\end{{{}}} does not match \\begin{{{}}} at {}",
+                close_tag, open_tag, open_pos
+            ),
+            Error::MissingEnd(open_tag, open_pos, _) => write!(
+                f,
+                "missing \\end{{{}}} for \\begin{{{}}} at {}",
+                open_tag, open_tag, open_pos
+            ),
+            Error::TagExpected(_) => write!(f, "expected a tag name"),
+            Error::InvalidTagName(_) => write!(f, "invalid tag name"),
+            Error::UnterminatedRaw(_) => write!(
+                f,
+                "unterminated raw block (use {{{{#...#}}}} for content with unbalanced braces)"
+            ),
+            Error::InvalidEscape(_) => write!(f, "invalid Unicode escape"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
 impl<'a> nom::error::ParseError<Span<'a>> for Error {
     fn from_error_kind(input: Span<'a>, _kind: ErrorKind) -> Self {
         if let Some(c) = input.fragment.chars().next() {
@@ -49,25 +106,111 @@ impl<'a> From<&Span<'a>> for Pos {
             filename: span.extra.clone(),
             line: span.line - 1,
             column: span.get_utf8_column() as u32 - 1,
+            offset: span.offset,
         }
     }
 }
 
+/// Apply a parser and also return the source range of the input that
+/// it consumed.
+fn ranged<'a, O, F>(f: F) -> impl Fn(Span<'a>) -> PResult<'a, (O, Range)>
+where
+    F: Fn(Span<'a>) -> PResult<'a, O>,
+{
+    move |input: Span<'a>| {
+        let (rest, res) = f(input)?;
+        let range = Range {
+            start: (&input).into(),
+            end: (&rest).into(),
+        };
+        Ok((rest, (res, range)))
+    }
+}
+
 pub fn text<'a>(input: Span<'a>) -> PResult<Item> {
     let text_char = none_of("{}[]\\");
-    map(many1(text_char), |cs| {
-        Item::new_text(cs.into_iter().collect(), (&input).into())
+    map(ranged(many1(text_char)), |(cs, range)| {
+        Item::new_text(cs.into_iter().collect(), range.start, range.end)
     })(input)
 }
 
+/// Parse an escaped character (`\\\\`, `\\{`, `\\}`, `\\[` or `\\]`)
+/// or a Unicode escape such as `\\u{2014}`, producing text.
+pub fn escape<'a>(input: Span<'a>) -> PResult<'a, Item> {
+    alt((
+        map(
+            ranged(preceded(char('\\'), one_of("\\{}[]"))),
+            |(c, range)| Item::new_text(c.to_string(), range.start, range.end),
+        ),
+        unicode_escape,
+    ))(input)
+}
+
+fn unicode_escape<'a>(input: Span<'a>) -> PResult<'a, Item> {
+    let (rest, (_, digits, _)) = tuple((
+        tag("\\u{"),
+        many_m_n(1, 6, one_of("0123456789abcdefABCDEF")),
+        char('}'),
+    ))(input)?;
+    let digits: String = digits.into_iter().collect();
+    match u32::from_str_radix(&digits, 16)
+        .ok()
+        .and_then(char::from_u32)
+    {
+        Some(c) => Ok((
+            rest,
+            Item::new_text(c.to_string(), (&input).into(), (&rest).into()),
+        )),
+        None => Err(nom::Err::Failure(Error::InvalidEscape((&input).into()))),
+    }
+}
+
 pub fn raw<'a>(input: Span<'a>) -> PResult<Item> {
-    // FIXME: support nesting
-    preceded(
-        tag("{{"),
-        cut(map(tuple((many0(none_of("{}")), tag("}}"))), |(cs, _)| {
-            Item::new_text(cs.into_iter().collect(), (&input).into())
-        })),
-    )(input)
+    let (rest, _) = tag("{{")(input)?;
+    match scan_raw(rest.fragment) {
+        Some((_, text, len)) => {
+            let rest = rest.slice(len..);
+            Ok((
+                rest,
+                Item::new_text(text.to_string(), (&input).into(), (&rest).into()),
+            ))
+        }
+        None => Err(nom::Err::Failure(Error::UnterminatedRaw((&input).into()))),
+    }
+}
+
+/// Find the end of a raw block, given the input following the opening
+/// `{{`. A raw block is either `{{...}}`, where the content may only
+/// contain balanced braces, or `{{#...#}}`, where the content is
+/// arbitrary text not containing the closing fence. The fence can be
+/// made unique by using more `#` characters, e.g. `{{##...##}}`. If
+/// there is no closing fence, the block is scanned as `{{...}}`, so
+/// that raw blocks starting with `#` keep working.
+/// Returns the number of `#` characters in the fence, the content and
+/// the length of the input up to and including the closing fence.
+pub fn scan_raw(s: &str) -> Option<(usize, &str, usize)> {
+    let fence = s.len() - s.trim_start_matches('#').len();
+    if fence > 0 {
+        let close = format!("{}}}}}", "#".repeat(fence));
+        if let Some(len) = s[fence..].find(&close) {
+            return Some((fence, &s[fence..fence + len], fence + len + close.len()));
+        }
+    }
+    let mut depth = 0;
+    for (i, c) in s.char_indices() {
+        if c == '{' {
+            depth += 1;
+        } else if c == '}' {
+            if depth > 0 {
+                depth -= 1;
+            } else if s[i..].starts_with("}}") {
+                return Some((0, &s[..i], i + 2));
+            } else {
+                return None;
+            }
+        }
+    }
+    None
 }
 
 pub fn tag_name<'a>() -> impl Fn(Span<'a>) -> PResult<String> {
@@ -92,22 +235,43 @@ pub fn pos_arg<'a>() -> impl Fn(Span<'a>) -> PResult<Doc> {
     preceded(char('{'), cut(map(tuple((doc, char('}'))), |(doc, _)| doc)))
 }
 
+type NamedArgs = (HashMap<String, Doc>, HashMap<String, Range>);
+
+fn split_named_args(args: Vec<((String, Doc), Range)>) -> NamedArgs {
+    let mut named_args = HashMap::new();
+    let mut named_arg_ranges = HashMap::new();
+    for ((name, doc), range) in args {
+        named_arg_ranges.insert(name.clone(), range);
+        named_args.insert(name, doc);
+    }
+    (named_args, named_arg_ranges)
+}
+
 pub fn element<'a>(input: Span<'a>) -> PResult<Item> {
     let (rest, (_, (tag, named_args, pos_args))) = tuple((
         char('\\'),
-        cut(tuple((tag_name(), many0(named_arg()), many1(pos_arg())))),
+        cut(tuple((
+            tag_name(),
+            many0(ranged(named_arg())),
+            many1(ranged(pos_arg())),
+        ))),
     ))(input)?;
 
     if tag == "begin" || tag == "end" {
         Err(nom::Err::Error(Error::InvalidTagName((&rest).into())))
     } else {
+        let (named_args, named_arg_ranges) = split_named_args(named_args);
+        let (pos_args, pos_arg_ranges) = pos_args.into_iter().unzip();
         Ok((
             rest,
             Element {
                 tag,
-                named_args: named_args.into_iter().collect(),
+                named_args,
                 pos_args,
+                named_arg_ranges,
+                pos_arg_ranges,
                 pos: (&input).into(),
+                end: (&rest).into(),
             }
             .into(),
         ))
@@ -115,21 +279,26 @@ pub fn element<'a>(input: Span<'a>) -> PResult<Item> {
 }
 
 pub fn long_element<'a>(input: Span<'a>) -> PResult<Item> {
-    let (rest, (_, (open_tag, _, named_args, mut pos_args, doc))) = tuple((
+    let (rest, (_, (open_tag, _, named_args, pos_args, (doc, doc_range)))) = tuple((
         tag("\\begin{"),
         cut(tuple((
             tag_name(),
             char('}'),
-            many0(named_arg()),
-            many0(pos_arg()),
-            doc,
+            many0(ranged(named_arg())),
+            many0(ranged(pos_arg())),
+            ranged(doc),
         ))),
     ))(input)?;
 
+    let end_pos = (&rest).into();
     let (rest, (_, close_tag, _)) =
         tuple((tag("\\end{"), tag_name(), char('}')))(rest).map_err(|err| {
             if let nom::Err::Error(Error::UnexpectedEOF(pos)) = err {
-                nom::Err::Failure(Error::MissingEnd(open_tag.to_string(), pos))
+                nom::Err::Failure(Error::MissingEnd(
+                    open_tag.to_string(),
+                    (&input).into(),
+                    pos,
+                ))
             } else {
                 err
             }
@@ -139,14 +308,20 @@ pub fn long_element<'a>(input: Span<'a>) -> PResult<Item> {
         if open_tag == "begin" || open_tag == "end" {
             Err(nom::Err::Failure(Error::InvalidTagName((&input).into())))
         } else {
+            let (named_args, named_arg_ranges) = split_named_args(named_args);
+            let (mut pos_args, mut pos_arg_ranges): (Vec<_>, Vec<_>) = pos_args.into_iter().unzip();
             pos_args.push(doc);
+            pos_arg_ranges.push(doc_range);
             Ok((
                 rest,
                 Element {
                     tag: open_tag,
-                    named_args: named_args.into_iter().collect(),
+                    named_args,
                     pos_args,
+                    named_arg_ranges,
+                    pos_arg_ranges,
                     pos: (&input).into(),
+                    end: (&rest).into(),
                 }
                 .into(),
             ))
@@ -155,27 +330,135 @@ pub fn long_element<'a>(input: Span<'a>) -> PResult<Item> {
         Err(nom::Err::Failure(Error::MismatchingTags(
             open_tag,
             close_tag,
-            (&rest).into(),
+            (&input).into(),
+            end_pos,
         )))
     }
 }
 
 pub fn doc<'a>(input: Span<'a>) -> PResult<Doc> {
-    let item = alt((text, raw, element, long_element));
     map(many0(item), |items| Doc(concat_texts(items)))(input)
 }
 
-pub fn parse_string(filename: Option<&Path>, s: &str) -> Result<Doc, Error> {
+fn item<'a>(input: Span<'a>) -> PResult<'a, Item> {
+    alt((text, escape, raw, element, long_element))(input)
+}
+
+fn end_tag<'a>(input: Span<'a>) -> PResult<'a, String> {
+    map(
+        tuple((tag("\\end{"), tag_name(), char('}'))),
+        |(_, tag, _)| tag,
+    )(input)
+}
+
+pub fn parse_string(filename: Option<&Path>, s: &str) -> Result<Doc, Vec<Error>> {
+    let (doc, errors) = parse_string_partial(filename, s);
+    if errors.is_empty() {
+        Ok(doc)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Parse a string, recovering from syntax errors. Returns the
+/// document parsed so far and every error encountered. After an
+/// error, parsing resumes after the next blank line; closing braces
+/// and `\end{...}` tags that belonged to skipped input are ignored.
+pub fn parse_string_partial(filename: Option<&Path>, s: &str) -> (Doc, Vec<Error>) {
     let filename = filename.map(|filename| Arc::new(filename.into()));
-    let input = Span::new_extra(s, &filename);
+    let mut input = Span::new_extra(s, &filename);
+    let mut items = vec![];
+    let mut errors = vec![];
+    let mut skipped = Unclosed::default();
+
+    while !input.fragment.is_empty() {
+        match item(input) {
+            Ok((rest, item)) => {
+                items.push(item);
+                input = rest;
+            }
+            Err(nom::Err::Error(_)) => {
+                /* The only inputs that no item accepts are stray
+                closing braces, brackets and \end tags. */
+                let (rest, err) = if let Ok((rest, _)) = end_tag(input) {
+                    if skipped.ends > 0 {
+                        skipped.ends -= 1;
+                        input = rest;
+                        continue;
+                    }
+                    (rest, Error::UnexpectedEnd((&input).into()))
+                } else {
+                    let c = input.fragment.chars().next().unwrap();
+                    if c == '}' && skipped.braces > 0 {
+                        skipped.braces -= 1;
+                        input = advance(input, c.len_utf8());
+                        continue;
+                    }
+                    (
+                        advance(input, c.len_utf8()),
+                        Error::UnexpectedChar(c, (&input).into()),
+                    )
+                };
+                errors.push(err);
+                input = rest;
+            }
+            Err(nom::Err::Failure(err)) => {
+                let start = input.offset;
+                let end = resync_offset(s, err.pos().offset.max(start));
+                skipped.add(&s[start..end]);
+                errors.push(err);
+                input = advance(input, end - start);
+            }
+            Err(nom::Err::Incomplete(_)) => unreachable!(),
+        }
+    }
+
+    (
+        crate::unindent::strip_common_indent(Doc(concat_texts(items))),
+        errors,
+    )
+}
 
-    let res = all_consuming(doc)(input);
+/// The number of braces and `\begin` tags left open by input that was
+/// skipped during error recovery.
+#[derive(Default)]
+struct Unclosed {
+    braces: usize,
+    ends: usize,
+}
+
+impl Unclosed {
+    fn add(&mut self, skipped: &str) {
+        let opened = skipped.matches('{').count();
+        let closed = skipped.matches('}').count();
+        self.braces += opened.saturating_sub(closed);
+        let begins = skipped.matches("\\begin{").count();
+        let ends = skipped.matches("\\end{").count();
+        self.ends += begins.saturating_sub(ends);
+    }
+}
 
-    match res {
-        Err(nom::Err::Error(err)) => unreachable!("ERROR {:?}", err),
-        Err(nom::Err::Failure(err)) => Err(err),
-        Err(nom::Err::Incomplete(_)) => unreachable!(),
-        Ok((_, doc)) => Ok(crate::unindent::strip_common_indent(doc)),
+fn advance(input: Span, n: usize) -> Span {
+    input.slice(n..)
+}
+
+/// Return the offset of the line break preceding the next blank line
+/// after `offset`, or the end of `s` if there is none. The blank line
+/// is not skipped, so that it still separates the surrounding
+/// paragraphs.
+fn resync_offset(s: &str, offset: usize) -> usize {
+    let mut line_start = match s[offset..].find('\n') {
+        Some(n) => offset + n + 1,
+        None => return s.len(),
+    };
+    loop {
+        let line_end = s[line_start..]
+            .find('\n')
+            .map_or(s.len(), |n| line_start + n + 1);
+        if s[line_start..line_end].chars().all(char::is_whitespace) {
+            return line_start - 1;
+        }
+        line_start = line_end;
     }
 }
 
@@ -191,7 +474,7 @@ mod test {
     }
 
     fn check_err(sst: &str, err: Error) {
-        assert_eq!(parse_string(None, sst), Err(err));
+        assert_eq!(parse_string(None, sst), Err(vec![err]));
     }
 
     #[test]
@@ -218,6 +501,7 @@ mod test {
                 filename: None,
                 line: 0,
                 column: 18,
+                offset: 18,
             }),
         )
     }
@@ -240,12 +524,145 @@ mod test {
                 Pos {
                     filename: None,
                     line: 0,
-                    column: 24,
+                    column: 0,
+                    offset: 0,
+                },
+                Pos {
+                    filename: None,
+                    line: 0,
+                    column: 15,
+                    offset: 15,
+                },
+            ),
+        );
+    }
+
+    #[test]
+    fn parse_begin_missing_end() {
+        check_err(
+            include_str!("../../test/long-element-eof.sst"),
+            Error::MissingEnd(
+                "emph".to_string(),
+                Pos {
+                    filename: None,
+                    line: 0,
+                    column: 6,
+                    offset: 6,
+                },
+                Pos {
+                    filename: None,
+                    line: 0,
+                    column: 21,
+                    offset: 21,
                 },
             ),
         );
     }
 
+    #[test]
+    fn parse_multiple_errors() {
+        let (doc, errors) =
+            parse_string_partial(None, include_str!("../../test/multiple-errors.sst"));
+        assert_eq!(
+            errors,
+            vec![
+                Error::UnexpectedChar(
+                    ']',
+                    Pos {
+                        filename: None,
+                        line: 0,
+                        column: 17,
+                        offset: 17,
+                    }
+                ),
+                Error::MismatchingTags(
+                    "emph".to_string(),
+                    "strong".to_string(),
+                    Pos {
+                        filename: None,
+                        line: 2,
+                        column: 7,
+                        offset: 28,
+                    },
+                    Pos {
+                        filename: None,
+                        line: 2,
+                        column: 28,
+                        offset: 49,
+                    }
+                ),
+                Error::UnexpectedChar(
+                    ']',
+                    Pos {
+                        filename: None,
+                        line: 4,
+                        column: 6,
+                        offset: 70,
+                    }
+                ),
+            ]
+        );
+        assert_eq!(doc.len(), 1);
+        assert_eq!(
+            doc[0].get_text(),
+            Some("Hello \n\nSecond \n\nThird  paragraph.\n")
+        );
+    }
+
+    #[test]
+    fn parse_raw() {
+        check_ok(
+            include_str!("../../test/raw.sst"),
+            include_str!("../../test/raw.json"),
+        );
+    }
+
+    #[test]
+    fn parse_raw_unbalanced() {
+        check_err(
+            include_str!("../../test/raw-unbalanced.sst"),
+            Error::UnterminatedRaw(Pos {
+                filename: None,
+                line: 0,
+                column: 0,
+                offset: 0,
+            }),
+        );
+    }
+
+    #[test]
+    fn parse_raw_hash() {
+        let doc = parse_string(None, "\\screen{{{# run as root\nnixos-rebuild switch}}}").unwrap();
+        match &doc[0] {
+            Item::Element(elem) => assert_eq!(
+                elem.pos_args[0][0].get_text(),
+                Some("# run as root\nnixos-rebuild switch")
+            ),
+            item => panic!("unexpected item: {:?}", item),
+        }
+    }
+
+    #[test]
+    fn parse_escape() {
+        check_ok(
+            include_str!("../../test/escape.sst"),
+            include_str!("../../test/escape.json"),
+        );
+    }
+
+    #[test]
+    fn parse_escape_invalid() {
+        check_err(
+            include_str!("../../test/escape-invalid.sst"),
+            Error::InvalidEscape(Pos {
+                filename: None,
+                line: 0,
+                column: 4,
+                offset: 4,
+            }),
+        );
+    }
+
     #[test]
     fn parse_indent() {
         check_ok(
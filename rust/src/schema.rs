@@ -1,27 +1,330 @@
-use crate::ast::Tag;
+use crate::ast::{Doc, Item, Pos, Tag};
+use crate::core;
+use regex::Regex;
+use serde::{de, Deserialize, Deserializer};
 use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
+#[derive(Clone)]
 pub struct Schema {
     pub start: Pattern,
+    /// Named patterns that can be referred to using `Pattern::Ref`.
+    pub patterns: HashMap<String, Pattern>,
     pub elements: HashMap<Tag, ElementType>,
 }
 
-type ElementType = Vec<Pattern>;
+/// The contents of a schema file. A schema can extend another schema
+/// (either `core` or a file relative to this one), in which case
+/// `start`, `patterns` and `elements` override those of the base
+/// schema, and `extend_patterns` adds alternatives to its pattern
+/// groups.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SchemaFile {
+    extends: Option<String>,
+    start: Option<Pattern>,
+    #[serde(default)]
+    patterns: HashMap<String, Pattern>,
+    #[serde(default)]
+    extend_patterns: HashMap<String, Vec<Pattern>>,
+    #[serde(default)]
+    elements: HashMap<Tag, ElementType>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ElementType {
+    #[serde(default)]
+    pub pos_args: Vec<Pattern>,
+    #[serde(default)]
+    pub named_args: HashMap<String, NamedArg>,
+}
+
+/// The declaration of a named argument of an element.
+#[derive(Deserialize, Debug, Clone)]
+pub struct NamedArg {
+    pub pattern: Pattern,
+    /// Whether the argument must be specified.
+    #[serde(default)]
+    pub required: bool,
+    /// The value used if the argument is not specified. It must
+    /// match `pattern`.
+    #[serde(default)]
+    pub default: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    IOError(PathBuf, io::Error),
+    BadJson(PathBuf, serde_json::Error),
+    MissingStart(PathBuf),
+    UndefinedPattern(PathBuf, String),
+    CyclicExtends(PathBuf),
+}
+
+impl Error {
+    pub fn pos(&self) -> Option<Pos> {
+        match self {
+            Error::IOError(_, _) => None,
+            Error::MissingStart(_) => None,
+            Error::UndefinedPattern(_, _) => None,
+            Error::CyclicExtends(_) => None,
+            Error::BadJson(path, err) => Some(Pos {
+                filename: Some(Arc::new(path.clone())),
+                line: err.line().saturating_sub(1) as u32,
+                column: err.column().saturating_sub(1) as u32,
+                offset: 0,
+            }),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::IOError(path, err) => {
+                write!(f, "cannot read schema '{}': {}", path.display(), err)
+            }
+            Error::BadJson(path, err) => {
+                write!(f, "invalid schema '{}': {}", path.display(), err)
+            }
+            Error::MissingStart(path) => write!(
+                f,
+                "schema '{}' neither defines a start pattern nor extends another schema",
+                path.display()
+            ),
+            Error::UndefinedPattern(path, name) => write!(
+                f,
+                "schema '{}' extends pattern '{}', which is not defined",
+                path.display(),
+                name
+            ),
+            Error::CyclicExtends(path) => {
+                write!(f, "schema '{}' extends itself", path.display())
+            }
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::IOError(_, err) => Some(err),
+            Error::BadJson(_, err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Read a schema from a JSON file.
+pub fn load(path: &Path) -> Result<Schema, Error> {
+    load_extending(path, &mut vec![])
+}
+
+fn load_extending(path: &Path, stack: &mut Vec<PathBuf>) -> Result<Schema, Error> {
+    let canonical = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    if stack.contains(&canonical) {
+        return Err(Error::CyclicExtends(path.into()));
+    }
+    let s = fs::read_to_string(path).map_err(|err| Error::IOError(path.into(), err))?;
+    let file: SchemaFile =
+        serde_json::from_str(&s).map_err(|err| Error::BadJson(path.into(), err))?;
+
+    let mut schema = match (file.extends, file.start) {
+        (Some(base), start) => {
+            let mut base = if base == "core" {
+                core::SCHEMA.clone()
+            } else {
+                stack.push(canonical);
+                let base =
+                    load_extending(&path.parent().unwrap_or(Path::new("")).join(base), stack)?;
+                stack.pop();
+                base
+            };
+            if let Some(start) = start {
+                base.start = start;
+            }
+            base
+        }
+        (None, Some(start)) => Schema {
+            start,
+            patterns: HashMap::new(),
+            elements: HashMap::new(),
+        },
+        (None, None) => return Err(Error::MissingStart(path.into())),
+    };
+
+    schema.patterns.extend(file.patterns);
+    for (name, alternatives) in file.extend_patterns {
+        if !schema.add_alternatives(&name, alternatives) {
+            return Err(Error::UndefinedPattern(path.into(), name));
+        }
+    }
+    schema.elements.extend(file.elements);
+
+    Ok(schema)
+}
+
+/// If the document starts with a `\schema{file}` declaration, remove
+/// it and return the path of the schema file, relative to the
+/// document.
+pub fn take_declaration(doc: &mut Doc) -> Option<PathBuf> {
+    let n = doc.iter().position(|item| !item.is_whitespace())?;
+    let path = match &doc[n] {
+        Item::Element(element) if element.tag == "schema" && element.pos_args.len() == 1 => {
+            let path: String = element.pos_args[0]
+                .iter()
+                .filter_map(Item::get_text)
+                .collect();
+            let path = PathBuf::from(path.trim());
+            match &element.pos.filename {
+                Some(filename) => filename.parent().unwrap_or(Path::new("")).join(path),
+                None => path,
+            }
+        }
+        _ => return None,
+    };
+    doc.remove(n);
+    Some(path)
+}
 
 impl Schema {
     pub fn add_element(&mut self, name: &str, pos_args: Vec<Pattern>) {
-        self.elements.insert(name.to_string(), pos_args);
+        self.elements.insert(
+            name.to_string(),
+            ElementType {
+                pos_args,
+                named_args: HashMap::new(),
+            },
+        );
+    }
+
+    /// Add alternatives to the pattern group `name`. Returns false if
+    /// there is no such group.
+    pub fn add_alternatives(&mut self, name: &str, alternatives: Vec<Pattern>) -> bool {
+        match self.patterns.get_mut(name) {
+            Some(Pattern::Choice(patterns)) => {
+                patterns.extend(alternatives);
+                true
+            }
+            Some(pattern) => {
+                let mut patterns = vec![pattern.clone()];
+                patterns.extend(alternatives);
+                *pattern = Pattern::Choice(patterns);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Allow the named argument `arg` on the previously added element
+    /// `name`.
+    pub fn add_named_arg(&mut self, name: &str, arg: &str, decl: NamedArg) {
+        self.elements
+            .get_mut(name)
+            .unwrap_or_else(|| panic!("element '{}' is not defined", name))
+            .named_args
+            .insert(arg.to_string(), decl);
     }
 }
 
-#[derive(Debug, Clone)]
+/// A pattern. In schema files, patterns without arguments are
+/// written as a string (e.g. `"Text"`), and other patterns as an
+/// object with a single key (e.g. `{"Element": "code"}`). The names
+/// are part of the file format, so they are spelled out below rather
+/// than derived from the variant names. See
+/// `test/example.schema.json` for an example of every pattern.
+#[derive(Deserialize, Debug, Clone)]
 pub enum Pattern {
+    /// `"Text"`
+    #[serde(rename = "Text")]
     Text,
+    /// An integer, optionally with an inclusive lower and upper
+    /// bound: `{"Int": [min, max]}`, where either can be `null`.
+    #[serde(rename = "Int")]
+    Int(Option<i64>, Option<i64>),
+    /// An identifier, e.g. the id of an element: `"Ident"`.
+    #[serde(rename = "Ident")]
+    Ident,
+    /// A URI or relative URI reference: `"Uri"`.
+    #[serde(rename = "Uri")]
+    Uri,
+    /// One of the given keywords: `{"Enum": ["a", "b"]}`.
+    #[serde(rename = "Enum")]
+    Enum(Vec<String>),
+    /// Text that matches the given regular expression in its
+    /// entirety: `{"Regex": "[0-9]+"}`.
+    #[serde(rename = "Regex")]
+    Regex(TextRegex),
+    /// `{"Element": "tag"}`
+    #[serde(rename = "Element")]
     Element(Tag),
+    /// `{"Para": pattern}`
+    #[serde(rename = "Para")]
     Para(Box<Pattern>),
+    /// `{"Seq": [pattern, ...]}`
+    #[serde(rename = "Seq")]
     Seq(Vec<Pattern>),
+    /// `{"Choice": [pattern, ...]}`
+    #[serde(rename = "Choice")]
     Choice(Vec<Pattern>),
+    /// Between `min` and `max` (or unboundedly many) repetitions:
+    /// `{"Many": [min, max, pattern]}`, where `max` can be `null`.
+    #[serde(rename = "Many")]
     Many(usize, Option<usize>, Box<Pattern>),
+    /// A reference to a pattern in `Schema::patterns`:
+    /// `{"Ref": "name"}`.
+    #[serde(rename = "Ref")]
+    Ref(String),
+}
+
+/// A regular expression that must match text in its entirety. It
+/// is compiled when the schema is loaded, so that invalid regular
+/// expressions are reported as schema errors.
+#[derive(Debug, Clone)]
+pub struct TextRegex {
+    source: String,
+    regex: Regex,
+}
+
+impl TextRegex {
+    pub fn new(source: &str) -> Result<Self, regex::Error> {
+        Ok(TextRegex {
+            source: source.to_string(),
+            regex: Regex::new(&format!("^(?:{})$", source))?,
+        })
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        self.regex.is_match(text)
+    }
+}
+
+impl fmt::Display for TextRegex {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for TextRegex {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let source = String::deserialize(deserializer)?;
+        TextRegex::new(&source).map_err(|err| {
+            /* Syntax errors are reported as a multi-line excerpt of
+            the (anchored) regex; keep only the actual message. */
+            let err = err.to_string();
+            let err = err.lines().last().unwrap_or_default();
+            de::Error::custom(format!(
+                "invalid regular expression /{}/: {}",
+                source,
+                err.trim_start_matches("error: ")
+            ))
+        })
+    }
 }
 
 impl Pattern {
@@ -29,6 +332,10 @@ impl Pattern {
         Pattern::Element(name.to_string())
     }
 
+    pub fn reference(name: &str) -> Self {
+        Pattern::Ref(name.to_string())
+    }
+
     pub fn para(pattern: Pattern) -> Self {
         Pattern::Para(Box::new(pattern))
     }
@@ -41,3 +348,59 @@ impl Pattern {
         Pattern::Many(1, None, Box::new(pattern))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{parser, validate};
+
+    fn check_file(name: &str, source: &str) {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../test")
+            .join(name);
+        let mut doc = parser::parse_string(Some(&path), source).unwrap();
+        let schema_file = take_declaration(&mut doc).unwrap();
+        let schema = load(&schema_file).unwrap();
+        validate::validate(&schema, &doc, &path).unwrap();
+    }
+
+    #[test]
+    fn load_schema() {
+        check_file("runbook.sst", include_str!("../../test/runbook.sst"));
+    }
+
+    #[test]
+    fn example_schema() {
+        check_file("example.sst", include_str!("../../test/example.sst"));
+    }
+
+    #[test]
+    fn extend_schema() {
+        check_file(
+            "admonitions.sst",
+            include_str!("../../test/admonitions.sst"),
+        );
+    }
+
+    #[test]
+    fn cyclic_extends() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../test/self-extend.schema.json");
+        match load(&path) {
+            Err(Error::CyclicExtends(_)) => {}
+            res => panic!("unexpected result: {:?}", res.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn bad_regex() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../test/bad-regex.schema.json");
+        match load(&path) {
+            Err(err @ Error::BadJson(_, _)) => {
+                assert!(err
+                    .to_string()
+                    .contains("invalid regular expression /v[0-9+/"))
+            }
+            res => panic!("unexpected result: {:?}", res.map(|_| ())),
+        }
+    }
+}
@@ -0,0 +1,472 @@
+/* Sanity checks on schemas: things that validation would only
+discover when a document happens to exercise them, or not at all. */
+
+use crate::ast::Tag;
+use crate::schema::*;
+use std::collections::{BTreeSet, HashSet};
+use std::error;
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    UndefinedElement(Tag, Location),
+    UndefinedPattern(String, Location),
+    UnreachableElement(Tag),
+    /// Two alternatives of a choice (numbered from 1) that can start
+    /// with the same elements, so that the first one is tried even
+    /// when only the second one matches.
+    AmbiguousChoice(Location, usize, usize, Vec<Token>),
+    /// A description of a pattern that can never match.
+    NeverMatches(String, Location),
+}
+
+/// Where in a schema a pattern occurs.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Location {
+    Start,
+    Pattern(String),
+    Arg(Tag, usize),
+    NamedArg(Tag, String),
+}
+
+/// Content that a pattern can start with.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Token {
+    Text,
+    Element(Tag),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::UndefinedElement(tag, location) => {
+                write!(f, "element \\{} is not defined (in {})", tag, location)
+            }
+            Error::UndefinedPattern(name, location) => {
+                write!(f, "pattern '{}' is not defined (in {})", name, location)
+            }
+            Error::UnreachableElement(tag) => {
+                write!(
+                    f,
+                    "element \\{} is not reachable from the start pattern",
+                    tag
+                )
+            }
+            Error::AmbiguousChoice(location, first, second, tokens) => {
+                let tokens: Vec<String> = tokens.iter().map(Token::to_string).collect();
+                write!(
+                    f,
+                    "ambiguous choice in {}: alternatives {} and {} can both start with {}",
+                    location,
+                    first,
+                    second,
+                    tokens.join(", ")
+                )
+            }
+            Error::NeverMatches(what, location) => {
+                write!(f, "{} in {} can never match", what, location)
+            }
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Location::Start => write!(f, "the start pattern"),
+            Location::Pattern(name) => write!(f, "pattern '{}'", name),
+            Location::Arg(tag, n) => write!(f, "argument {} of \\{}", n + 1, tag),
+            Location::NamedArg(tag, name) => write!(f, "named argument '{}' of \\{}", name, tag),
+        }
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Text => write!(f, "text"),
+            Token::Element(tag) => write!(f, "\\{}", tag),
+        }
+    }
+}
+
+/// The depth of pattern references beyond which references are
+/// assumed to match nothing, to cope with recursive patterns.
+const MAX_DEPTH: usize = 16;
+
+/// Check that every element and pattern used by a schema is defined,
+/// that every element can occur in a document, and that every part
+/// of every pattern can match something.
+pub fn check(schema: &Schema) -> Result<(), Vec<Error>> {
+    let mut errors = vec![];
+
+    let mut roots = vec![(Location::Start, &schema.start)];
+    let mut names: Vec<&String> = schema.patterns.keys().collect();
+    names.sort();
+    for name in names {
+        roots.push((Location::Pattern(name.clone()), &schema.patterns[name]));
+    }
+    let mut tags: Vec<&Tag> = schema.elements.keys().collect();
+    tags.sort();
+    for tag in &tags {
+        let element_type = &schema.elements[*tag];
+        for (n, pattern) in element_type.pos_args.iter().enumerate() {
+            roots.push((Location::Arg((*tag).clone(), n), pattern));
+        }
+        let mut arg_names: Vec<&String> = element_type.named_args.keys().collect();
+        arg_names.sort();
+        for arg_name in arg_names {
+            roots.push((
+                Location::NamedArg((*tag).clone(), arg_name.clone()),
+                &element_type.named_args[arg_name].pattern,
+            ));
+        }
+    }
+
+    for (location, pattern) in &roots {
+        check_pattern(schema, pattern, location, &mut errors);
+    }
+
+    let reachable = reachable_elements(schema);
+    for tag in tags {
+        if !reachable.contains(tag) {
+            errors.push(Error::UnreachableElement(tag.clone()));
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+fn check_pattern(schema: &Schema, pattern: &Pattern, location: &Location, errors: &mut Vec<Error>) {
+    let never_matches = |what: String, errors: &mut Vec<Error>| {
+        errors.push(Error::NeverMatches(what, location.clone()));
+    };
+
+    match pattern {
+        Pattern::Text | Pattern::Ident | Pattern::Uri | Pattern::Regex(_) => {}
+        Pattern::Int(Some(min), Some(max)) if min > max => {
+            never_matches(format!("an integer between {} and {}", min, max), errors)
+        }
+        Pattern::Int(_, _) => {}
+        Pattern::Enum(keywords) => {
+            if keywords.is_empty() {
+                never_matches("an empty enumeration".to_string(), errors);
+            }
+        }
+        Pattern::Element(tag) => {
+            if !schema.elements.contains_key(tag) {
+                errors.push(Error::UndefinedElement(tag.clone(), location.clone()));
+            }
+        }
+        Pattern::Ref(name) => {
+            if !schema.patterns.contains_key(name) {
+                errors.push(Error::UndefinedPattern(name.clone(), location.clone()));
+            }
+        }
+        Pattern::Para(pattern) => check_pattern(schema, pattern, location, errors),
+        Pattern::Seq(patterns) => {
+            for pattern in patterns {
+                check_pattern(schema, pattern, location, errors);
+            }
+            /* A repetition without an upper bound consumes everything
+            it can, so a later pattern that can only start with
+            such content never matches. */
+            for (n, pattern) in patterns.iter().enumerate() {
+                let starts = first(schema, pattern, 0);
+                if starts.is_empty() || nullable(schema, pattern, 0) {
+                    continue;
+                }
+                for prev in patterns[..n].iter().rev() {
+                    if let Pattern::Many(_, None, repeated) = prev {
+                        if starts.is_subset(&decides(schema, repeated, 0)) {
+                            never_matches(
+                                format!(
+                                    "{} after a repetition of {}",
+                                    describe(pattern),
+                                    describe(repeated)
+                                ),
+                                errors,
+                            );
+                            break;
+                        }
+                    }
+                    if !nullable(schema, prev, 0) {
+                        break;
+                    }
+                }
+            }
+        }
+        Pattern::Choice(patterns) => {
+            if patterns.is_empty() {
+                never_matches("a choice without alternatives".to_string(), errors);
+            }
+            for pattern in patterns {
+                check_pattern(schema, pattern, location, errors);
+            }
+            /* Alternatives are tried in order, so an alternative
+            that can only start with content that an earlier one
+            always matches is never used. */
+            let mut decided = BTreeSet::new();
+            for (n, pattern) in patterns.iter().enumerate() {
+                let starts = first(schema, pattern, 0);
+                if !starts.is_empty() && !nullable(schema, pattern, 0) && starts.is_subset(&decided)
+                {
+                    never_matches(format!("alternative {} of a choice", n + 1), errors);
+                } else {
+                    for (m, prev) in patterns[..n].iter().enumerate() {
+                        let overlap: Vec<Token> = starts
+                            .intersection(&first(schema, prev, 0))
+                            .filter(|token| matches!(token, Token::Element(_)))
+                            .cloned()
+                            .collect();
+                        if !overlap.is_empty() {
+                            errors.push(Error::AmbiguousChoice(
+                                location.clone(),
+                                m + 1,
+                                n + 1,
+                                overlap,
+                            ));
+                        }
+                    }
+                }
+                decided.extend(decides(schema, pattern, 0));
+            }
+        }
+        Pattern::Many(min, Some(max), pattern) => {
+            if min > max {
+                never_matches(
+                    format!("a repetition of at least {} and at most {} items", min, max),
+                    errors,
+                );
+            }
+            check_pattern(schema, pattern, location, errors);
+        }
+        Pattern::Many(_, None, pattern) => check_pattern(schema, pattern, location, errors),
+    }
+}
+
+/// Return the elements that can occur in a document, i.e. those
+/// reachable from the start pattern.
+fn reachable_elements(schema: &Schema) -> HashSet<&Tag> {
+    fn visit<'a>(
+        schema: &'a Schema,
+        pattern: &'a Pattern,
+        elements: &mut HashSet<&'a Tag>,
+        patterns: &mut HashSet<&'a str>,
+    ) {
+        match pattern {
+            Pattern::Element(tag) => {
+                if let Some(element_type) = schema.elements.get(tag) {
+                    if elements.insert(tag) {
+                        for pattern in &element_type.pos_args {
+                            visit(schema, pattern, elements, patterns);
+                        }
+                        for arg in element_type.named_args.values() {
+                            visit(schema, &arg.pattern, elements, patterns);
+                        }
+                    }
+                }
+            }
+            Pattern::Ref(name) => {
+                if let Some(pattern) = schema.patterns.get(name) {
+                    if patterns.insert(name) {
+                        visit(schema, pattern, elements, patterns);
+                    }
+                }
+            }
+            Pattern::Para(pattern) | Pattern::Many(_, _, pattern) => {
+                visit(schema, pattern, elements, patterns)
+            }
+            Pattern::Seq(ps) | Pattern::Choice(ps) => {
+                for pattern in ps {
+                    visit(schema, pattern, elements, patterns);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut elements = HashSet::new();
+    visit(schema, &schema.start, &mut elements, &mut HashSet::new());
+    elements
+}
+
+/// Whether `pattern` can match empty content.
+fn nullable(schema: &Schema, pattern: &Pattern, depth: usize) -> bool {
+    match pattern {
+        Pattern::Seq(patterns) => patterns.iter().all(|p| nullable(schema, p, depth)),
+        Pattern::Choice(patterns) => patterns.iter().any(|p| nullable(schema, p, depth)),
+        Pattern::Many(min, _, pattern) => *min == 0 || nullable(schema, pattern, depth),
+        Pattern::Ref(name) => {
+            depth < MAX_DEPTH
+                && schema
+                    .patterns
+                    .get(name)
+                    .map_or(false, |p| nullable(schema, p, depth + 1))
+        }
+        _ => false,
+    }
+}
+
+/// Return the content that `pattern` can start with.
+fn first(schema: &Schema, pattern: &Pattern, depth: usize) -> BTreeSet<Token> {
+    let mut tokens = BTreeSet::new();
+    match pattern {
+        Pattern::Text
+        | Pattern::Int(_, _)
+        | Pattern::Ident
+        | Pattern::Uri
+        | Pattern::Enum(_)
+        | Pattern::Regex(_) => {
+            tokens.insert(Token::Text);
+        }
+        Pattern::Element(tag) => {
+            tokens.insert(Token::Element(tag.clone()));
+        }
+        Pattern::Para(pattern) => tokens = first(schema, pattern, depth),
+        Pattern::Seq(patterns) => {
+            for pattern in patterns {
+                tokens.extend(first(schema, pattern, depth));
+                if !nullable(schema, pattern, depth) {
+                    break;
+                }
+            }
+        }
+        Pattern::Choice(patterns) => {
+            for pattern in patterns {
+                tokens.extend(first(schema, pattern, depth));
+            }
+        }
+        Pattern::Many(_, max, pattern) => {
+            if *max != Some(0) {
+                tokens = first(schema, pattern, depth);
+            }
+        }
+        Pattern::Ref(name) => {
+            if let Some(pattern) = schema.patterns.get(name) {
+                if depth < MAX_DEPTH {
+                    tokens = first(schema, pattern, depth + 1);
+                }
+            }
+        }
+    }
+    tokens
+}
+
+/// Return the content on which `pattern` always succeeds, i.e. for
+/// which no later alternative is ever tried.
+fn decides(schema: &Schema, pattern: &Pattern, depth: usize) -> BTreeSet<Token> {
+    let mut tokens = BTreeSet::new();
+    match pattern {
+        Pattern::Text => {
+            tokens.insert(Token::Text);
+        }
+        Pattern::Element(tag) => {
+            tokens.insert(Token::Element(tag.clone()));
+        }
+        Pattern::Para(pattern) => tokens = decides(schema, pattern, depth),
+        Pattern::Seq(patterns) => {
+            if let Some((pattern, rest)) = patterns.split_first() {
+                if rest.iter().all(|p| nullable(schema, p, depth)) {
+                    tokens = decides(schema, pattern, depth);
+                }
+            }
+        }
+        Pattern::Choice(patterns) => {
+            for pattern in patterns {
+                tokens.extend(decides(schema, pattern, depth));
+            }
+        }
+        Pattern::Many(min, max, pattern) if *min <= 1 && *max != Some(0) => {
+            tokens = decides(schema, pattern, depth);
+        }
+        Pattern::Ref(name) => {
+            if let Some(pattern) = schema.patterns.get(name) {
+                if depth < MAX_DEPTH {
+                    tokens = decides(schema, pattern, depth + 1);
+                }
+            }
+        }
+        _ => {}
+    }
+    tokens
+}
+
+/// Describe a pattern for use in error messages.
+fn describe(pattern: &Pattern) -> String {
+    match pattern {
+        Pattern::Text => "text".to_string(),
+        Pattern::Int(_, _) => "an integer".to_string(),
+        Pattern::Ident => "an identifier".to_string(),
+        Pattern::Uri => "a URI".to_string(),
+        Pattern::Enum(_) => "an enumeration".to_string(),
+        Pattern::Regex(re) => format!("text matching /{}/", re),
+        Pattern::Element(tag) => format!("\\{}", tag),
+        Pattern::Para(_) => "a paragraph".to_string(),
+        Pattern::Seq(_) => "a sequence".to_string(),
+        Pattern::Choice(_) => "a choice".to_string(),
+        Pattern::Many(_, _, _) => "a repetition".to_string(),
+        Pattern::Ref(name) => format!("pattern '{}'", name),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core;
+    use std::collections::HashMap;
+
+    #[test]
+    fn core_schema() {
+        assert!(check(&core::SCHEMA).is_ok());
+    }
+
+    #[test]
+    fn schema_errors() {
+        let mut schema = Schema {
+            start: Pattern::many(Pattern::Choice(vec![
+                Pattern::Seq(vec![Pattern::element("a"), Pattern::element("b")]),
+                Pattern::Seq(vec![Pattern::element("a"), Pattern::element("c")]),
+                Pattern::element("c"),
+                Pattern::element("c"),
+                Pattern::reference("block"),
+            ])),
+            patterns: HashMap::new(),
+            elements: HashMap::new(),
+        };
+        schema.add_element(
+            "a",
+            vec![Pattern::Seq(vec![
+                Pattern::many(Pattern::Text),
+                Pattern::Int(None, None),
+            ])],
+        );
+        schema.add_element("b", vec![Pattern::element("d")]);
+        schema.add_element("c", vec![]);
+        schema.add_element("e", vec![Pattern::Int(Some(2), Some(1))]);
+
+        let messages: Vec<String> = check(&schema)
+            .unwrap_err()
+            .iter()
+            .map(Error::to_string)
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                "pattern 'block' is not defined (in the start pattern)",
+                "ambiguous choice in the start pattern: alternatives 1 and 2 can both start with \\a",
+                "alternative 4 of a choice in the start pattern can never match",
+                "an integer after a repetition of text in argument 1 of \\a can never match",
+                "element \\d is not defined (in argument 1 of \\b)",
+                "an integer between 2 and 1 in argument 1 of \\e can never match",
+                "element \\e is not reachable from the start pattern",
+            ]
+        );
+    }
+}
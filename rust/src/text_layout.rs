@@ -64,6 +64,10 @@ struct FullStyle {
 pub enum Color {
     Normal,
     Red,
+    Green,
+    Yellow,
+    Blue,
+    Magenta,
 }
 
 impl FullStyle {
@@ -318,8 +322,13 @@ fn emit_ansi_delta(dest: &mut String, old: &FullStyle, new: &FullStyle) {
         if new.strikethrough {
             dest.push_str(";9");
         }
-        if new.color == Color::Red {
-            dest.push_str(";31");
+        match new.color {
+            Color::Normal => {}
+            Color::Red => dest.push_str(";31"),
+            Color::Green => dest.push_str(";32"),
+            Color::Yellow => dest.push_str(";33"),
+            Color::Blue => dest.push_str(";34"),
+            Color::Magenta => dest.push_str(";35"),
         }
         dest.push_str("m");
     }
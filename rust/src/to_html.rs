@@ -0,0 +1,310 @@
+use crate::{number, validate::*, view};
+
+struct ToHtml<'doc> {
+    numbers: number::Numbers<'doc>,
+}
+
+pub fn to_html(doc: &Instance) -> String {
+    let state = ToHtml {
+        numbers: number::Numbers::create(doc),
+    };
+
+    let mut body = String::new();
+    state.blocks(view::Content::new(doc), 1, &mut body);
+
+    let mut title = String::new();
+    state.doc_title(doc, &mut title);
+
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n</head>\n<body>\n{}</body>\n</html>\n",
+        title, body
+    )
+}
+
+/// Escape a string for use in HTML element content or in a quoted
+/// attribute value.
+pub fn escape(s: &str, out: &mut String) {
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+}
+
+impl<'doc> ToHtml<'doc> {
+    /// Emit the plain text of the document title, if any, for use in
+    /// the HTML `<title>` element.
+    fn doc_title(&self, doc: &'doc Instance, out: &mut String) {
+        if let Some(title) = view::Element::new(doc.unchoice()).and_then(|e| e.title()) {
+            escape(title.plain_text().trim(), out);
+        }
+    }
+
+    /// Emit the opening `<section>` tag and the numbered heading of a
+    /// chapter, section or subsection.
+    fn open_section(&self, element: view::Element<'doc>, level: usize, out: &mut String) {
+        let toc_entry = self
+            .numbers
+            .get_toc_entry(element.instance())
+            .unwrap_or_else(|| panic!("Expected TOC entry for: {:?}", element.instance()));
+        let number = toc_entry.to_string();
+        out.push_str(&format!("<section class=\"{}\" id=\"", element.tag()));
+        escape(&element.id().unwrap_or_else(|| anchor_id(&number)), out);
+        out.push_str("\">\n");
+        out.push_str(&format!("<h{}><span class=\"number\">", level.min(6)));
+        escape(&number, out);
+        out.push_str("</span> ");
+        self.inlines(toc_entry.title, out);
+        out.push_str(&format!("</h{}>\n", level.min(6)));
+    }
+
+    /// Emit a sequence of blocks. `level` is the heading level of any
+    /// sectioning elements among them.
+    fn blocks(&self, content: view::Content<'doc>, level: usize, out: &mut String) {
+        for node in content.nodes() {
+            match node {
+                view::Node::Para(para) => {
+                    let mut texts = String::new();
+                    self.inlines(para, &mut texts);
+                    out.push_str("<p>");
+                    out.push_str(texts.trim());
+                    out.push_str("</p>\n");
+                }
+                view::Node::Element(element) => self.block(element, level, out),
+                view::Node::Text(_) | view::Node::Value(_) => {}
+            }
+        }
+    }
+
+    fn block(&self, element: view::Element<'doc>, level: usize, out: &mut String) {
+        let tag = element.tag();
+        match tag {
+            "book" => {
+                out.push_str("<h1 class=\"book-title\"");
+                id_attr(element, out);
+                out.push('>');
+                self.inlines(element.arg(0), out);
+                out.push_str("</h1>\n");
+                self.blocks(element.body(), 2, out);
+            }
+            "article" => {
+                out.push_str("<article");
+                id_attr(element, out);
+                out.push_str(">\n<h1 class=\"article-title\">");
+                self.inlines(element.arg(0), out);
+                out.push_str("</h1>\n");
+                self.blocks(element.body(), 2, out);
+                out.push_str("</article>\n");
+            }
+            "part" => {
+                out.push_str("<div class=\"part\"");
+                id_attr(element, out);
+                out.push_str(">\n");
+                out.push_str(&format!("<h{} class=\"part-title\">", level.min(6)));
+                self.inlines(element.arg(0), out);
+                out.push_str(&format!("</h{}>\n", level.min(6)));
+                self.blocks(element.body(), level + 1, out);
+                out.push_str("</div>\n");
+            }
+            "chapter" | "section" | "subsection" => {
+                self.open_section(element, level, out);
+                self.blocks(element.body(), level + 1, out);
+                out.push_str("</section>\n");
+            }
+            "simplesect" => {
+                out.push_str("<section class=\"simplesect\"");
+                id_attr(element, out);
+                out.push_str(">\n");
+                out.push_str(&format!("<h{}>", level.min(6)));
+                self.inlines(element.arg(0), out);
+                out.push_str(&format!("</h{}>\n", level.min(6)));
+                self.blocks(element.body(), level + 1, out);
+                out.push_str("</section>\n");
+            }
+            "dinkus" => {
+                out.push_str("<hr class=\"dinkus\">\n");
+            }
+            "listing" | "screen" => {
+                out.push_str(&format!("<pre class=\"{}\"", tag));
+                id_attr(element, out);
+                out.push_str("><code");
+                if let Some(language) = element.named_arg("language") {
+                    out.push_str(" class=\"language-");
+                    escape(language.plain_text().trim(), out);
+                    out.push('"');
+                }
+                out.push('>');
+                self.inlines(element.arg(0), out);
+                out.push_str("</code></pre>\n");
+            }
+            "ol" | "ul" | "procedure" => {
+                if tag == "ul" {
+                    out.push_str("<ul");
+                } else if tag == "ol" {
+                    out.push_str("<ol");
+                } else {
+                    out.push_str("<ol class=\"procedure\"");
+                }
+                id_attr(element, out);
+                out.push_str(">\n");
+                for step in element.arg(0).elements() {
+                    out.push_str("<li>\n");
+                    self.blocks(step.arg(0), level, out);
+                    out.push_str("</li>\n");
+                }
+                out.push_str(if tag == "ul" { "</ul>\n" } else { "</ol>\n" });
+            }
+            "namedlist" => {
+                out.push_str("<dl");
+                id_attr(element, out);
+                out.push_str(">\n");
+                for item in element.children("item") {
+                    out.push_str("<dt>");
+                    self.inlines(item.arg(0), out);
+                    out.push_str("</dt>\n<dd>\n");
+                    self.blocks(item.arg(1), level, out);
+                    out.push_str("</dd>\n");
+                }
+                out.push_str("</dl>\n");
+            }
+            _ => {
+                out.push_str("<div class=\"unhandled\">");
+                escape(tag, out);
+                out.push_str("</div>\n");
+            }
+        }
+    }
+
+    fn inlines(&self, content: view::Content<'doc>, out: &mut String) {
+        for node in content.nodes() {
+            match node {
+                view::Node::Text(s) => escape(s, out),
+                view::Node::Value(value) => escape(&value.to_string(), out),
+                view::Node::Para(para) => self.inlines(para, out),
+                view::Node::Element(element) => self.inline_element(element, out),
+            }
+        }
+    }
+
+    fn inline_element(&self, element: view::Element<'doc>, out: &mut String) {
+        let (open, close) = match element.tag() {
+            "emph" => ("<em>", "</em>"),
+            "strong" => ("<strong>", "</strong>"),
+            "code" => ("<code>", "</code>"),
+            "todo" => ("<mark class=\"todo\">", "</mark>"),
+            "filename" => ("<code class=\"filename\">", "</code>"),
+            "envar" => ("<code class=\"envar\">", "</code>"),
+            "command" => ("<code class=\"command\">", "</code>"),
+            "replaceable" => ("<var>", "</var>"),
+            "uri" => {
+                let uri = element.arg(0).plain_text();
+                out.push_str("<a class=\"uri\" href=\"");
+                escape(&uri, out);
+                out.push_str("\">");
+                escape(&uri, out);
+                out.push_str("</a>");
+                return;
+            }
+            "link" => {
+                out.push_str("<a href=\"");
+                escape(&element.arg(0).plain_text(), out);
+                out.push_str("\">");
+                self.inlines(element.arg(1), out);
+                out.push_str("</a>");
+                return;
+            }
+            "xref" => {
+                let id = element.arg(0).plain_text();
+                let id = id.trim();
+                out.push_str("<a class=\"xref\" href=\"#");
+                escape(id, out);
+                out.push_str("\">");
+                match self.numbers.get_xref_label(id) {
+                    Some((label, title)) => {
+                        escape(&label, out);
+                        if let Some(title) = title {
+                            if !label.is_empty() {
+                                out.push_str(", ");
+                            }
+                            self.inlines(title, out);
+                        }
+                    }
+                    None => escape(id, out),
+                }
+                out.push_str("</a>");
+                return;
+            }
+            tag => {
+                out.push_str("<span class=\"unhandled\">");
+                escape(tag, out);
+                out.push_str("</span>");
+                return;
+            }
+        };
+        out.push_str(open);
+        self.inlines(element.arg(0), out);
+        out.push_str(close);
+    }
+}
+
+/// Return the anchor id for a numbered chapter, section or
+/// subsection (e.g. `sec-2.3`).
+fn anchor_id(number: &str) -> String {
+    format!("sec-{}", number)
+}
+
+/// Emit an `id` attribute for an element that has an `id` argument.
+fn id_attr(element: view::Element, out: &mut String) {
+    if let Some(id) = element.id() {
+        out.push_str(" id=\"");
+        escape(&id, out);
+        out.push('"');
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn html() {
+        let sst = "\\chapter[id=intro]{Introduction}{
+  Some \\emph{emphasis} & \\code{<code>} with a \\link{https://example.org/}{link}.
+
+  \\ul{\\li{One}}
+
+  \\section{Details}{See \\xref{intro}.}
+}";
+        assert_eq!(
+            view::convert(sst, to_html),
+            r##"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<title>Introduction</title>
+</head>
+<body>
+<section class="chapter" id="intro">
+<h1><span class="number">1</span> Introduction</h1>
+<p>Some <em>emphasis</em> &amp; <code>&lt;code&gt;</code> with a <a href="https://example.org/">link</a>.</p>
+<ul>
+<li>
+<p>One</p>
+</li>
+</ul>
+<section class="section" id="sec-1.1">
+<h2><span class="number">1.1</span> Details</h2>
+<p>See <a class="xref" href="#intro">Chapter 1, Introduction</a>.</p>
+</section>
+</section>
+</body>
+</html>
+"##
+        );
+    }
+}
@@ -0,0 +1,424 @@
+use crate::{number, validate::*, view};
+
+struct ToMarkdown<'doc> {
+    numbers: number::Numbers<'doc>,
+    numbered: bool,
+}
+
+/// Convert a document to CommonMark (with GitHub extensions). If
+/// `numbered` is set, chapter and section headings are prefixed with
+/// their number.
+pub fn to_markdown(doc: &Instance, numbered: bool) -> String {
+    let state = ToMarkdown {
+        numbers: number::Numbers::create(doc),
+        numbered,
+    };
+
+    let mut blocks = vec![];
+    state.blocks(view::Content::new(doc), 1, &mut blocks);
+
+    let mut out = blocks.join("\n\n");
+    out.push('\n');
+    out
+}
+
+/// Escape characters that have a meaning in Markdown inline content,
+/// and collapse whitespace (line breaks in the source are not
+/// significant).
+fn escape(s: &str, out: &mut String) {
+    let mut in_ws = false;
+    for c in s.chars() {
+        if c.is_whitespace() {
+            if !in_ws {
+                out.push(' ');
+            }
+            in_ws = true;
+            continue;
+        }
+        in_ws = false;
+        if "\\`*_[]<>#|~&".contains(c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+}
+
+/// Make sure that a paragraph is not mistaken for a list item,
+/// heading or other block construct.
+fn escape_para_start(para: &str) -> String {
+    let digits = para.len() - para.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    if para.starts_with(&['-', '+', '=', '>'][..])
+        || (digits > 0 && para[digits..].starts_with(&['.', ')'][..]))
+    {
+        format!("{}\\{}", &para[..digits], &para[digits..])
+    } else {
+        para.to_string()
+    }
+}
+
+/// Return a code span containing `s`, using a backtick string that
+/// does not occur in `s`.
+fn code_span(s: &str) -> String {
+    let s: String = s.split_whitespace().collect::<Vec<_>>().join(" ");
+    let fence = "`".repeat(longest_run(&s, '`') + 1);
+    if s.starts_with('`') || s.ends_with('`') {
+        format!("{} {} {}", fence, s, fence)
+    } else {
+        format!("{}{}{}", fence, s, fence)
+    }
+}
+
+/// Return a link destination for `uri`, using the `<...>` form if it
+/// contains characters that would end a plain destination.
+fn link_destination(uri: &str) -> String {
+    if !uri.is_empty() && !uri.contains(|c: char| c.is_whitespace() || "()<>\\".contains(c)) {
+        return uri.to_string();
+    }
+    let mut out = String::from("<");
+    for c in uri.chars() {
+        match c {
+            '<' | '>' | '\\' => {
+                out.push('\\');
+                out.push(c);
+            }
+            '\n' => out.push_str("%0A"),
+            '\r' => out.push_str("%0D"),
+            _ => out.push(c),
+        }
+    }
+    out.push('>');
+    out
+}
+
+/// Test whether `uri` can be written as an autolink, i.e. it has a
+/// scheme and no spaces or angle brackets.
+fn is_autolink(uri: &str) -> bool {
+    let scheme_len = uri.find(':').unwrap_or(0);
+    let scheme = &uri[..scheme_len];
+    (2..=32).contains(&scheme_len)
+        && scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+        && scheme
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "+.-".contains(c))
+        && !uri.contains(|c: char| c.is_whitespace() || c.is_control() || c == '<' || c == '>')
+}
+
+fn longest_run(s: &str, c: char) -> usize {
+    let mut longest = 0;
+    let mut cur = 0;
+    for c2 in s.chars() {
+        cur = if c2 == c { cur + 1 } else { 0 };
+        longest = longest.max(cur);
+    }
+    longest
+}
+
+/// Indent all lines but the first of a list item by `width`.
+fn indent(s: &str, width: usize) -> String {
+    let mut out = String::new();
+    for (n, line) in s.lines().enumerate() {
+        if n > 0 {
+            out.push('\n');
+            if !line.is_empty() {
+                out.push_str(&" ".repeat(width));
+            }
+        }
+        out.push_str(line);
+    }
+    out
+}
+
+impl<'doc> ToMarkdown<'doc> {
+    fn heading(&self, title: view::Content<'doc>, number: Option<String>, level: usize) -> String {
+        let mut out = "#".repeat(level.min(6));
+        out.push(' ');
+        if let Some(number) = number {
+            out.push_str(&number);
+            out.push(' ');
+        }
+        self.inlines(title, &mut out);
+        out.trim_end().to_string()
+    }
+
+    /// Emit an anchor for an element that has an `id` argument, so
+    /// that cross-references to it work.
+    fn anchor(&self, element: view::Element<'doc>, blocks: &mut Vec<String>) {
+        if let Some(id) = element.id() {
+            let mut out = "<a id=\"".to_string();
+            out.push_str(&id);
+            out.push_str("\"></a>");
+            blocks.push(out);
+        }
+    }
+
+    fn blocks(&self, content: view::Content<'doc>, level: usize, blocks: &mut Vec<String>) {
+        let mut prev_list = None;
+        for node in content.nodes() {
+            match node {
+                view::Node::Para(para) => {
+                    let mut out = String::new();
+                    self.inlines(para, &mut out);
+                    blocks.push(escape_para_start(out.trim()));
+                    prev_list = None;
+                }
+                view::Node::Element(element) => {
+                    /* Adjacent lists of the same kind would
+                    otherwise be merged into one. */
+                    let list = match element.tag() {
+                        "ul" | "namedlist" => Some("-"),
+                        "ol" | "procedure" => Some("1."),
+                        _ => None,
+                    };
+                    if list.is_some() && list == prev_list {
+                        blocks.push("<!-- -->".to_string());
+                    }
+                    self.block(element, level, blocks);
+                    prev_list = list;
+                }
+                view::Node::Text(_) | view::Node::Value(_) => {}
+            }
+        }
+    }
+
+    fn block(&self, element: view::Element<'doc>, level: usize, blocks: &mut Vec<String>) {
+        let tag = element.tag();
+        match tag {
+            "book" | "article" | "part" => {
+                self.anchor(element, blocks);
+                blocks.push(self.heading(element.arg(0), None, level));
+                self.blocks(element.body(), level + 1, blocks);
+            }
+            "chapter" | "section" | "subsection" => {
+                let toc_entry = self
+                    .numbers
+                    .get_toc_entry(element.instance())
+                    .unwrap_or_else(|| panic!("Expected TOC entry for: {:?}", element.instance()));
+                let number = if self.numbered {
+                    Some(toc_entry.to_string())
+                } else {
+                    None
+                };
+                self.anchor(element, blocks);
+                blocks.push(self.heading(toc_entry.title, number, level));
+                self.blocks(element.body(), level + 1, blocks);
+            }
+            "simplesect" => {
+                self.anchor(element, blocks);
+                blocks.push(self.heading(element.arg(0), None, level));
+                self.blocks(element.body(), level + 1, blocks);
+            }
+            "dinkus" => blocks.push("* * *".to_string()),
+            "listing" | "screen" => {
+                self.anchor(element, blocks);
+                let text = element.arg(0).plain_text();
+                let text = text.trim_start_matches('\n').trim_end();
+                let fence = "`".repeat(longest_run(text, '`').max(2) + 1);
+                let language = element
+                    .named_arg("language")
+                    .map(|language| language.plain_text().trim().to_string())
+                    .unwrap_or_default();
+                blocks.push(format!("{}{}\n{}\n{}", fence, language, text, fence));
+            }
+            "ol" | "ul" | "procedure" => {
+                self.anchor(element, blocks);
+                let mut items = vec![];
+                for (n, item) in element.arg(0).elements().enumerate() {
+                    let marker = if tag == "ul" {
+                        "-".to_string()
+                    } else {
+                        format!("{}.", n + 1)
+                    };
+                    let mut item_blocks = vec![];
+                    self.blocks(item.arg(0), level, &mut item_blocks);
+                    items.push(format!(
+                        "{} {}",
+                        marker,
+                        indent(&item_blocks.join("\n\n"), marker.len() + 1)
+                    ));
+                }
+                blocks.push(items.join("\n"));
+            }
+            "namedlist" => {
+                /* CommonMark has no definition lists, so use a
+                bulleted list of terms with the definition below
+                each term. */
+                self.anchor(element, blocks);
+                let mut items = vec![];
+                for item in element.children("item") {
+                    let mut term = String::new();
+                    self.inlines(item.arg(0), &mut term);
+                    let mut out = format!("- **{}**", term.trim());
+                    let mut item_blocks = vec![];
+                    self.blocks(item.arg(1), level, &mut item_blocks);
+                    if !item_blocks.is_empty() {
+                        out.push_str("\n\n  ");
+                        out.push_str(&indent(&item_blocks.join("\n\n"), 2));
+                    }
+                    items.push(out);
+                }
+                blocks.push(items.join("\n\n"));
+            }
+            _ => blocks.push(format!("<!-- unhandled: {} -->", tag)),
+        }
+    }
+
+    fn inlines(&self, content: view::Content<'doc>, out: &mut String) {
+        for node in content.nodes() {
+            match node {
+                view::Node::Text(s) => escape(s, out),
+                view::Node::Value(value) => escape(&value.to_string(), out),
+                view::Node::Para(para) => self.inlines(para, out),
+                view::Node::Element(element) => self.inline(element, out),
+            }
+        }
+    }
+
+    fn inline(&self, element: view::Element<'doc>, out: &mut String) {
+        let delimiter = match element.tag() {
+            "emph" | "replaceable" => "*",
+            "strong" => "**",
+            "code" | "filename" | "envar" | "command" => {
+                out.push_str(&code_span(&element.arg(0).plain_text()));
+                return;
+            }
+            "todo" => {
+                out.push_str("**[");
+                self.inlines(element.arg(0), out);
+                out.push_str("]**");
+                return;
+            }
+            "uri" => {
+                let uri = element.arg(0).plain_text();
+                let uri = uri.trim();
+                if is_autolink(uri) {
+                    out.push('<');
+                    out.push_str(uri);
+                    out.push('>');
+                } else {
+                    out.push('[');
+                    escape(uri, out);
+                    out.push_str("](");
+                    out.push_str(&link_destination(uri));
+                    out.push(')');
+                }
+                return;
+            }
+            "link" => {
+                out.push('[');
+                self.inlines(element.arg(1), out);
+                out.push_str("](");
+                out.push_str(&link_destination(element.arg(0).plain_text().trim()));
+                out.push(')');
+                return;
+            }
+            "xref" => {
+                let id = element.arg(0).plain_text();
+                let id = id.trim();
+                out.push('[');
+                match self.numbers.get_xref_label(id) {
+                    Some((label, title)) => {
+                        escape(&label, out);
+                        if let Some(title) = title {
+                            if !label.is_empty() {
+                                out.push_str(", ");
+                            }
+                            self.inlines(title, out);
+                        }
+                    }
+                    None => escape(id, out),
+                }
+                out.push_str("](#");
+                out.push_str(id);
+                out.push(')');
+                return;
+            }
+            tag => {
+                out.push_str(&format!("<!-- unhandled: {} -->", tag));
+                return;
+            }
+        };
+        let mut inner = String::new();
+        self.inlines(element.arg(0), &mut inner);
+        /* Emphasis can't start or end with whitespace. */
+        let trimmed = inner.trim();
+        out.push_str(&inner[..inner.len() - inner.trim_start().len()]);
+        out.push_str(delimiter);
+        out.push_str(trimmed);
+        out.push_str(delimiter);
+        out.push_str(&inner[inner.trim_end().len()..]);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn convert(sst: &str, numbered: bool) -> String {
+        view::convert(sst, |instance| to_markdown(instance, numbered))
+    }
+
+    #[test]
+    fn markdown() {
+        let sst = "\\chapter[id=intro]{Introduction}{
+  Some \\emph{emphasis}, \\strong{strong} text and \\code{a `tick`}
+  with a \\link{https://example.org/}{link} and a_b.
+
+  \\listing[language=rust]{{{fn main() {}}}}
+
+  \\ul{\\li{One} \\li{Two
+
+    More.}}
+
+  \\procedure{\\step{Do \\command{this}.}}
+
+  \\namedlist{\\item{Term}{Definition.}}
+
+  \\section{Details}{See \\xref{intro}.}
+}";
+        assert_eq!(
+            convert(sst, false),
+            r#"<a id="intro"></a>
+
+# Introduction
+
+Some *emphasis*, **strong** text and `` a `tick` `` with a [link](https://example.org/) and a\_b.
+
+```rust
+fn main() {}
+```
+
+- One
+- Two
+
+  More.
+
+1. Do `this`.
+
+- **Term**
+
+  Definition.
+
+## Details
+
+See [Chapter 1, Introduction](#intro).
+"#
+        );
+        assert!(convert(sst, true).contains("\n## 1.1 Details\n"));
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!(convert("A &amp; B", false), "A \\&amp; B\n");
+        assert_eq!(
+            convert(
+                "\\link{https://example.org/a_(b}{x} \\uri{https://example.org/}",
+                false
+            ),
+            "[x](<https://example.org/a_(b>) <https://example.org/>\n"
+        );
+        assert_eq!(
+            convert("\\uri{docs/a_b.html}", false),
+            "[docs/a\\_b.html](docs/a_b.html)\n"
+        );
+    }
+}
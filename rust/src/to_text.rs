@@ -1,4 +1,4 @@
-use crate::{number, text_layout::*, validate::*};
+use crate::{number, text_layout::*, validate::*, view};
 
 struct ToText<'doc> {
     numbers: number::Numbers<'doc>,
@@ -12,100 +12,31 @@ pub fn to_text(doc: &Instance, max_width: usize) -> String {
     };
 
     let mut blocks = vec![];
-    state.toplevel(doc, &mut blocks);
+    state.blocks(view::Content::new(doc), &mut blocks);
 
     format(max_width, &Content::TB(blocks).into())
 }
 
 impl<'doc> ToText<'doc> {
-    fn toplevel(&self, doc: &Instance, blocks: &mut Blocks) {
-        let doc = doc.unchoice();
-        match doc {
-            Instance::Many(_) => {
-                self.blocks(doc, blocks);
-            }
-            Instance::Element(tag, _) if tag == "book" => {
-                self.book(doc, blocks);
-            }
-            Instance::Element(tag, _) if tag == "article" => {
-                self.article(doc, blocks);
-            }
-            Instance::Element(tag, _) if tag == "part" => {
-                self.part(doc, blocks);
-            }
-            Instance::Element(tag, _) if tag == "chapter" => {
-                self.chapter(doc, blocks);
-            }
-            Instance::Element(tag, _) if tag == "section" => {
-                self.section(doc, blocks);
-            }
-            _ => panic!(),
-        }
-    }
-
-    fn book(&self, doc: &Instance, blocks: &mut Blocks) {
-        match doc {
-            Instance::Element(tag, children) if tag == "book" => {
-                let title = &children[0];
-                let body = &children[1];
-                let mut texts = vec![];
-                self.inlines(title, &mut texts);
-                blocks.push(Content::Para(texts).into());
-                for item in body.iter() {
-                    self.chapter(item, blocks);
-                }
-            }
-            _ => panic!(),
-        }
-    }
-
-    fn article(&self, doc: &Instance, blocks: &mut Blocks) {
-        match doc {
-            Instance::Element(tag, children) if tag == "article" => {
-                let title = &children[0];
-                let body = &children[1].seq();
-                let mut texts = vec![];
-                self.inlines(title, &mut texts);
-                blocks.push(Content::Para(texts).into());
-                self.blocks(&body[0], blocks);
-                for s in body[1].iter() {
-                    self.simplesect(s, blocks);
-                }
-                for item in body[2].iter() {
-                    self.section(item, blocks);
-                }
-            }
-            _ => panic!(),
-        }
-    }
-
-    fn part(&self, doc: &Instance, blocks: &mut Blocks) {
-        match doc {
-            Instance::Element(tag, children) if tag == "part" => {
-                let title = &children[0];
-                let body = &children[1];
-                let mut texts = vec![];
-                self.inlines(title, &mut texts);
-                blocks.push(Content::Para(texts).into());
-                for item in body.iter() {
-                    self.chapter(item, blocks);
+    fn blocks(&self, content: view::Content<'doc>, blocks: &mut Blocks) {
+        for node in content.nodes() {
+            match node {
+                view::Node::Para(para) => {
+                    let mut texts = vec![];
+                    self.inlines(para, &mut texts);
+                    blocks.push(Content::Para(texts).into());
                 }
+                view::Node::Element(element) => self.block(element, blocks),
+                view::Node::Text(_) | view::Node::Value(_) => {}
             }
-            _ => panic!(),
         }
     }
 
-    /*
-    fn get_title(&self, doc: &Instance) -> String {
-        self.numbers.get_toc_entry(doc).unwrap().to_string()
-    }
-    */
-
-    fn emit_title(&self, doc: &Instance, blocks: &mut Blocks) {
+    fn emit_title(&self, element: view::Element<'doc>, blocks: &mut Blocks) {
         let toc_entry = self
             .numbers
-            .get_toc_entry(doc)
-            .expect(&format!("Expected TOC entry for: {:?}", doc));
+            .get_toc_entry(element.instance())
+            .unwrap_or_else(|| panic!("Expected TOC entry for: {:?}", element.instance()));
         let mut texts = vec![];
         texts.push(Text::Text(toc_entry.to_string()));
         texts.push(Text::Text(" ".to_string()));
@@ -119,82 +50,25 @@ impl<'doc> ToText<'doc> {
         );
     }
 
-    fn chapter(&self, doc: &Instance, blocks: &mut Blocks) {
-        match doc {
-            Instance::Element(tag, children) if tag == "chapter" => {
-                let body = &children[1].seq();
-                self.emit_title(&doc, blocks);
-                self.blocks(&body[0], blocks);
-                for s in body[1].iter() {
-                    self.simplesect(s, blocks);
-                }
-                for item in body[2].iter() {
-                    self.section(item, blocks);
-                }
-            }
-            _ => panic!(),
-        }
-    }
-
-    fn section(&self, doc: &Instance, blocks: &mut Blocks) {
-        match doc {
-            Instance::Element(tag, children) if tag == "section" => {
-                let body = &children[1].seq();
-                self.emit_title(&doc, blocks);
-                self.blocks(&body[0], blocks);
-                for s in body[1].iter() {
-                    self.simplesect(s, blocks);
-                }
-                for item in body[2].iter() {
-                    self.subsection(item, blocks);
-                }
+    fn block(&self, element: view::Element<'doc>, blocks: &mut Blocks) {
+        match element.tag() {
+            "book" | "article" | "part" => {
+                let mut texts = vec![];
+                self.inlines(element.arg(0), &mut texts);
+                blocks.push(Content::Para(texts).into());
+                self.blocks(element.body(), blocks);
             }
-            _ => panic!(),
-        }
-    }
-
-    fn subsection(&self, doc: &Instance, blocks: &mut Blocks) {
-        match doc {
-            Instance::Element(tag, children) if tag == "subsection" => {
-                let body = &children[1].seq();
-                self.emit_title(&doc, blocks);
-                self.blocks(&body[0], blocks);
-                for s in body[1].iter() {
-                    self.simplesect(s, blocks);
-                }
+            "chapter" | "section" | "subsection" => {
+                self.emit_title(element, blocks);
+                self.blocks(element.body(), blocks);
             }
-            _ => panic!(),
-        }
-    }
-
-    fn simplesect(&self, doc: &Instance, blocks: &mut Blocks) {
-        match doc {
-            Instance::Element(tag, children) if tag == "simplesect" => {
-                let title = &children[0];
-                let body = &children[1].seq();
+            "simplesect" => {
                 let mut texts = vec![];
-                self.inlines(title, &mut texts);
+                self.inlines(element.arg(0), &mut texts);
                 blocks.push(Content::Para(vec![Text::Styled(Style::Underline, texts)]).into());
-                self.blocks(&body[0], blocks);
+                self.blocks(element.body(), blocks);
             }
-            _ => panic!(),
-        }
-    }
-
-    fn blocks(&self, doc: &Instance, blocks: &mut Blocks) {
-        for item in doc.iter() {
-            self.block(item, blocks);
-        }
-    }
-
-    fn block(&self, doc: &Instance, blocks: &mut Blocks) {
-        match doc.unchoice() {
-            Instance::Para(para) => {
-                let mut texts = vec![];
-                self.inlines(para, &mut texts);
-                blocks.push(Content::Para(texts).into());
-            }
-            Instance::Element(tag, _) if tag == "dinkus" => {
+            "dinkus" => {
                 let s = "* * *";
                 blocks.push(
                     Content::Pre(vec![
@@ -208,9 +82,9 @@ impl<'doc> ToText<'doc> {
                     .into(),
                 );
             }
-            Instance::Element(tag, children) if tag == "listing" || tag == "screen" => {
+            "listing" | "screen" => {
                 let mut texts = vec![];
-                self.inlines(&children[0], &mut texts);
+                self.inlines(element.arg(0), &mut texts);
                 blocks.push(
                     Content::Table(vec![vec![
                         Block::new(Content::Pre(vec![Text::Text("   ".to_string())])),
@@ -219,20 +93,13 @@ impl<'doc> ToText<'doc> {
                     .into(),
                 );
             }
-            Instance::Element(tag, children)
-                if tag == "ol" || tag == "ul" || tag == "procedure" =>
-            {
+            "ol" | "ul" | "procedure" => {
                 let mut rows = vec![];
-                for (n, step) in children[0].many().iter().enumerate() {
+                for (n, step) in element.arg(0).elements().enumerate() {
                     let mut blocks = vec![];
-                    match step {
-                        Instance::Element(tag, children) if tag == "li" || tag == "step" => {
-                            self.blocks(&children[0], &mut blocks);
-                        }
-                        _ => unreachable!(),
-                    }
+                    self.blocks(step.arg(0), &mut blocks);
                     rows.push(vec![
-                        Content::Pre(vec![Text::Text(if tag == "ul" {
+                        Content::Pre(vec![Text::Text(if element.tag() == "ul" {
                             "*".to_string()
                         } else {
                             format!("{}.", n + 1)
@@ -243,81 +110,180 @@ impl<'doc> ToText<'doc> {
                 }
                 blocks.push(Content::Table(rows).into());
             }
-            Instance::Element(tag, children) if tag == "namedlist" => {
-                for step in children[0].many().iter() {
-                    match step {
-                        Instance::Element(tag, children) if tag == "item" => {
-                            let mut texts = vec![];
-                            texts.push(Text::Text("* ".to_string()));
-                            self.inlines(&children[0], &mut texts);
-                            blocks
-                                .push(Content::Para(vec![Text::Styled(Style::Bold, texts)]).into());
+            "namedlist" => {
+                for item in element.children("item") {
+                    let mut texts = vec![];
+                    texts.push(Text::Text("* ".to_string()));
+                    self.inlines(item.arg(0), &mut texts);
+                    blocks.push(Content::Para(vec![Text::Styled(Style::Bold, texts)]).into());
 
-                            let mut blocks2 = vec![];
-                            self.blocks(&children[1], &mut blocks2);
-                            blocks.push(
-                                Content::Table(vec![vec![
-                                    Block::new(Content::Pre(vec![Text::Text(" ".to_string())])),
-                                    Block::new(Content::TB(blocks2)),
-                                ]])
-                                .into(),
-                            );
-                        }
-                        _ => unreachable!(),
-                    }
+                    let mut blocks2 = vec![];
+                    self.blocks(item.arg(1), &mut blocks2);
+                    blocks.push(
+                        Content::Table(vec![vec![
+                            Block::new(Content::Pre(vec![Text::Text(" ".to_string())])),
+                            Block::new(Content::TB(blocks2)),
+                        ]])
+                        .into(),
+                    );
                 }
             }
-            _ => panic!("Unsupported: {:?}", doc.unchoice()),
+            _ => {
+                blocks.push(Content::Para(vec![Text::Text("<UNHANDLED>".to_string())]).into());
+            }
         }
     }
 
-    fn inlines(&self, doc: &Instance, texts: &mut Texts) {
-        if let Instance::Many(docs) = doc {
-            for d in docs {
-                match d.unchoice() {
-                    Instance::Text(s) => {
-                        texts.push(Text::Text(s.clone()));
-                    }
-                    Instance::Element(tag, children) if tag == "emph" => {
-                        let mut texts2 = vec![];
-                        self.inlines(&children[0], &mut texts2);
-                        texts.push(Text::Styled(Style::Italic, texts2));
-                    }
-                    Instance::Element(tag, children) if tag == "strong" => {
-                        let mut texts2 = vec![];
-                        self.inlines(&children[0], &mut texts2);
-                        texts.push(Text::Styled(Style::Bold, texts2));
-                    }
-                    Instance::Element(tag, children) if tag == "todo" => {
-                        let mut texts2 = vec![];
-                        texts2.push(Text::Text("[".to_string()));
-                        self.inlines(&children[0], &mut texts2);
-                        texts2.push(Text::Text("]".to_string()));
-                        texts.push(Text::Styled(
-                            Style::Bold,
-                            vec![Text::Styled(Style::Color(Color::Red), texts2)],
-                        ));
-                    }
-                    Instance::Element(tag, children) if tag == "code" || tag == "filename" => {
-                        let mut texts2 = vec![];
-                        self.inlines(&children[0], &mut texts2);
-                        texts.push(Text::Styled(Style::Bold, texts2));
-                    }
-                    Instance::Element(tag, children) if tag == "link" => {
-                        self.inlines(&children[1], texts);
-                        if let Instance::Text(s) = &children[0] {
-                            texts.push(Text::Text(" (".to_string()));
-                            texts.push(Text::Styled(Style::Bold, vec![Text::Text(s.clone())]));
-                            texts.push(Text::Text(")".to_string()));
+    fn inlines(&self, content: view::Content<'doc>, texts: &mut Texts) {
+        for node in content.nodes() {
+            match node {
+                view::Node::Text(s) => texts.push(Text::Text(s.to_string())),
+                view::Node::Value(value) => texts.push(Text::Text(value.to_string())),
+                view::Node::Para(para) => self.inlines(para, texts),
+                view::Node::Element(element) => self.inline(element, texts),
+            }
+        }
+    }
+
+    fn inline(&self, element: view::Element<'doc>, texts: &mut Texts) {
+        match element.tag() {
+            "emph" => {
+                let mut texts2 = vec![];
+                self.inlines(element.arg(0), &mut texts2);
+                texts.push(Text::Styled(Style::Italic, texts2));
+            }
+            "strong" | "code" | "filename" => {
+                let mut texts2 = vec![];
+                self.inlines(element.arg(0), &mut texts2);
+                texts.push(Text::Styled(Style::Bold, texts2));
+            }
+            "todo" => {
+                let mut texts2 = vec![];
+                texts2.push(Text::Text("[".to_string()));
+                self.inlines(element.arg(0), &mut texts2);
+                texts2.push(Text::Text("]".to_string()));
+                texts.push(Text::Styled(
+                    Style::Bold,
+                    vec![Text::Styled(Style::Color(Color::Red), texts2)],
+                ));
+            }
+            "command" => {
+                let mut texts2 = vec![];
+                self.inlines(element.arg(0), &mut texts2);
+                texts.push(Text::Styled(
+                    Style::Bold,
+                    vec![Text::Styled(Style::Color(Color::Green), texts2)],
+                ));
+            }
+            "envar" => {
+                let mut texts2 = vec![];
+                self.inlines(element.arg(0), &mut texts2);
+                texts.push(Text::Styled(Style::Color(Color::Magenta), texts2));
+            }
+            "replaceable" => {
+                let mut texts2 = vec![];
+                self.inlines(element.arg(0), &mut texts2);
+                texts.push(Text::Styled(
+                    Style::Italic,
+                    vec![Text::Styled(Style::Color(Color::Yellow), texts2)],
+                ));
+            }
+            "uri" => {
+                let mut texts2 = vec![];
+                self.inlines(element.arg(0), &mut texts2);
+                texts.push(Text::Styled(
+                    Style::Underline,
+                    vec![Text::Styled(Style::Color(Color::Blue), texts2)],
+                ));
+            }
+            "link" => {
+                self.inlines(element.arg(1), texts);
+                texts.push(Text::Text(" (".to_string()));
+                texts.push(Text::Styled(
+                    Style::Bold,
+                    vec![Text::Text(element.arg(0).plain_text())],
+                ));
+                texts.push(Text::Text(")".to_string()));
+            }
+            "xref" => {
+                let id = element.arg(0).plain_text();
+                let mut texts2 = vec![];
+                match self.numbers.get_xref_label(id.trim()) {
+                    Some((label, title)) => {
+                        texts2.push(Text::Text(label.clone()));
+                        if let Some(title) = title {
+                            if !label.is_empty() {
+                                texts2.push(Text::Text(", ".to_string()));
+                            }
+                            self.inlines(title, &mut texts2);
                         }
                     }
-                    _ => {
-                        texts.push(Text::Text("<UNHANDLED>".to_string()));
+                    None => texts2.push(Text::Text(id)),
+                }
+                texts.push(Text::Styled(Style::Color(Color::Blue), texts2));
+            }
+            _ => {
+                texts.push(Text::Text("<UNHANDLED>".to_string()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::core;
+    use std::collections::BTreeSet;
+
+    fn collect_tags(content: view::Content, tags: &mut BTreeSet<String>) {
+        for node in content.nodes() {
+            match node {
+                view::Node::Element(element) => {
+                    tags.insert(element.tag().to_string());
+                    let mut n = 0;
+                    while !element.arg(n).nodes().is_empty() {
+                        collect_tags(element.arg(n), tags);
+                        n += 1;
                     }
                 }
+                view::Node::Para(para) => collect_tags(para, tags),
+                _ => {}
             }
-        } else {
-            panic!("{:?}", doc)
         }
     }
+
+    #[test]
+    fn all_elements_handled() {
+        let docs = [
+            "\\book{Book}{\\chapter[id=ch]{Chapter}{
+               Text with \\emph{emph}, \\strong{strong}, \\code{code}, \\todo{todo},
+               \\filename{file}, \\envar{HOME}, \\uri{https://example.org/},
+               \\command{ls}, \\link{https://example.org/}{a link}, \\xref{ch} and
+               \\replaceable{name}.
+
+               \\dinkus{}
+               \\listing{{{x = 1}}}
+               \\screen{$ \\replaceable{command}}
+               \\ul{\\li{One}}
+               \\ol{\\li{Two}}
+               \\procedure{\\step{Three}}
+               \\namedlist{\\item{Term}{Definition}}
+               \\simplesect{Simple}{Text.}
+               \\section{Section}{Text. \\subsection{Subsection}{Text.}}
+             }}",
+            "\\article{Article}{Text.}",
+            "\\part{Part}{\\chapter{Chapter}{Text.}}",
+        ];
+
+        let mut tags = BTreeSet::new();
+        for sst in &docs {
+            view::convert(sst, |instance| {
+                collect_tags(view::Content::new(instance), &mut tags);
+                assert!(!to_text(instance, 80).contains("<UNHANDLED>"));
+            });
+        }
+
+        let schema_tags: BTreeSet<String> = core::SCHEMA.elements.keys().cloned().collect();
+        assert_eq!(tags, schema_tags);
+    }
 }
@@ -1,90 +1,240 @@
 use crate::{ast::*, schema::*};
 use serde::Serialize;
-use std::mem;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::error;
+use std::fmt;
 use std::path::Path;
 use std::str::Chars;
 use std::sync::Arc;
 
 #[derive(Debug, Clone)]
 pub enum Error {
-    Expected(Vec<Expected>, Pos),
+    Expected(Vec<Expected>, Found, Pos),
     WrongArgCount(Tag, usize, usize, Pos),
     WrongElementContent(Tag, Pos, Box<Error>),
+    UnknownNamedArg(Tag, String, Pos),
+    MissingNamedArg(Tag, String, Pos),
     SchemaError(Tag),
+    UndefinedPattern(String),
 }
 
 #[derive(Debug, Clone)]
 pub enum Expected {
     Text,
+    /// A typed value, described e.g. as "an integer".
+    Value(String),
     Para,
     Element(Tag),
     End,
 }
 
+/// What was found instead of the expected content.
+#[derive(Debug, Clone)]
+pub enum Found {
+    Text,
+    /// Text that is not a valid value of the expected type.
+    Value(String),
+    Element(Tag),
+    End,
+}
+
 impl Error {
     fn is_fatal(&self) -> bool {
         match self {
             Error::WrongArgCount(_, _, _, _) => true,
             Error::WrongElementContent(_, _, _) => true,
+            Error::UnknownNamedArg(_, _, _) => true,
+            Error::MissingNamedArg(_, _, _) => true,
             Error::SchemaError(_) => true,
+            Error::UndefinedPattern(_) => true,
             _ => false,
         }
     }
+
+    pub fn pos(&self) -> Option<&Pos> {
+        match self {
+            Error::Expected(_, _, pos) => Some(pos),
+            Error::WrongArgCount(_, _, _, pos) => Some(pos),
+            Error::WrongElementContent(_, pos, _) => Some(pos),
+            Error::UnknownNamedArg(_, _, pos) => Some(pos),
+            Error::MissingNamedArg(_, _, pos) => Some(pos),
+            Error::SchemaError(_) => None,
+            Error::UndefinedPattern(_) => None,
+        }
+    }
+
+    /// The position of the error itself, rather than that of the
+    /// elements whose content it is in.
+    fn innermost_pos(&self) -> Option<&Pos> {
+        match self {
+            Error::WrongElementContent(_, pos, err) => err.innermost_pos().or(Some(pos)),
+            _ => self.pos(),
+        }
+    }
 }
 
-#[derive(Serialize, Debug)]
-pub enum Instance {
-    Text(String),
-    Element(Tag, Vec<Instance>),
-    Para(Box<Instance>),
-    Seq(Vec<Instance>),
-    Choice(usize, Box<Instance>),
-    Many(Vec<Instance>),
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Expected(expected, found, _) => {
+                let mut alternatives: Vec<String> = vec![];
+                for exp in expected {
+                    let exp = exp.to_string();
+                    if !alternatives.contains(&exp) {
+                        alternatives.push(exp);
+                    }
+                }
+                write!(f, "expected ")?;
+                for (n, exp) in alternatives.iter().enumerate() {
+                    if n > 0 {
+                        write!(
+                            f,
+                            "{}",
+                            if n + 1 == alternatives.len() {
+                                " or "
+                            } else {
+                                ", "
+                            }
+                        )?;
+                    }
+                    write!(f, "{}", exp)?;
+                }
+                write!(f, ", found {}", found)
+            }
+            Error::WrongArgCount(tag, expected, got, _) => write!(
+                f,
+                "\\{} expects {} arguments, but got {}",
+                tag, expected, got
+            ),
+            Error::WrongElementContent(tag, _, err) => {
+                write!(f, "invalid contents of \\{}: {}", tag, err)
+            }
+            Error::UnknownNamedArg(tag, arg, _) => {
+                write!(f, "\\{} does not take a named argument '{}'", tag, arg)
+            }
+            Error::MissingNamedArg(tag, arg, _) => {
+                write!(f, "\\{} requires a named argument '{}'", tag, arg)
+            }
+            Error::SchemaError(tag) => {
+                write!(f, "element \\{} is not defined in the schema", tag)
+            }
+            Error::UndefinedPattern(name) => {
+                write!(f, "pattern '{}' is not defined in the schema", name)
+            }
+        }
+    }
 }
 
-impl Instance {
-    pub fn unchoice(&self) -> &Self {
+impl error::Error for Error {}
+
+impl fmt::Display for Expected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Instance::Choice(_, i) => &i,
-            _ => &self,
+            Expected::Text => write!(f, "text"),
+            Expected::Value(description) => write!(f, "{}", description),
+            Expected::Para => write!(f, "a paragraph"),
+            Expected::Element(tag) => write!(f, "\\{}", tag),
+            Expected::End => write!(f, "end of content"),
         }
     }
+}
 
-    pub fn seq(&self) -> &Vec<Instance> {
+impl fmt::Display for Found {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Instance::Seq(is) => is,
-            _ => panic!(),
+            Found::Text => write!(f, "text"),
+            Found::Value(text) => write!(f, "'{}'", text),
+            Found::Element(tag) => write!(f, "\\{}", tag),
+            Found::End => write!(f, "end of content"),
         }
     }
+}
 
-    pub fn many(&self) -> &Vec<Instance> {
+#[derive(Serialize, Debug, Clone)]
+pub enum Instance {
+    Text(String, Range),
+    Value(Value, Range),
+    Element(Tag, BTreeMap<String, Instance>, Vec<Instance>, Range),
+    Para(Box<Instance>, Range),
+    Seq(Vec<Instance>),
+    Choice(usize, Box<Instance>),
+    Many(Vec<Instance>),
+}
+
+/// The value of a typed text pattern.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Int(i64),
+    Ident(String),
+    Uri(String),
+    Keyword(String),
+    String(String),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Instance::Many(is) => is,
-            _ => panic!(),
+            Value::Int(n) => write!(f, "{}", n),
+            Value::Ident(s) | Value::Uri(s) | Value::Keyword(s) | Value::String(s) => {
+                write!(f, "{}", s)
+            }
         }
     }
+}
 
-    pub fn iter(&self) -> &Vec<Instance> {
+impl Instance {
+    pub fn unchoice(&self) -> &Self {
         match self {
-            Instance::Many(is) => is,
-            _ => panic!(),
+            Instance::Choice(_, i) => &i,
+            _ => &self,
         }
     }
 
     pub fn is_whitespace(&self) -> bool {
         match self {
-            Instance::Text(s) => s.chars().all(char::is_whitespace),
-            Instance::Element(_, _) => false,
-            Instance::Para(i) => i.is_whitespace(),
+            Instance::Text(s, _) => s.chars().all(char::is_whitespace),
+            Instance::Value(_, _) => false,
+            Instance::Element(_, _, _, _) => false,
+            Instance::Para(i, _) => i.is_whitespace(),
             Instance::Seq(is) => is.iter().all(Instance::is_whitespace),
             Instance::Choice(_, i) => i.is_whitespace(),
             Instance::Many(is) => is.iter().all(Instance::is_whitespace),
         }
     }
+
+    /// Concatenate the text in an instance, ignoring markup.
+    pub fn plain_text(&self, out: &mut String) {
+        match self {
+            Instance::Text(s, _) => out.push_str(s),
+            Instance::Value(value, _) => out.push_str(&value.to_string()),
+            Instance::Element(_, _, children, _) => {
+                for child in children {
+                    child.plain_text(out);
+                }
+            }
+            Instance::Para(child, _) => child.plain_text(out),
+            Instance::Seq(children) => {
+                for child in children {
+                    child.plain_text(out);
+                }
+            }
+            Instance::Choice(_, child) => child.plain_text(out),
+            Instance::Many(children) => {
+                for child in children {
+                    child.plain_text(out);
+                }
+            }
+        }
+    }
 }
 
-pub fn validate(schema: &Schema, doc: &Doc, filename: &Path) -> Result<Instance, Error> {
-    validate_full_doc(
+/// Validate a document against a schema, returning all errors in
+/// the document. After an error, validation resumes by skipping the
+/// offending item.
+pub fn validate(schema: &Schema, doc: &Doc, filename: &Path) -> Result<Instance, Vec<Error>> {
+    let mut errors = vec![];
+    let instance = validate_recovering(
         schema,
         &schema.start,
         doc,
@@ -92,8 +242,23 @@ pub fn validate(schema: &Schema, doc: &Doc, filename: &Path) -> Result<Instance,
             filename: Some(Arc::new(filename.into())),
             line: 0,
             column: 0,
+            offset: 0,
         },
-    )
+        &mut errors,
+    );
+    if errors.is_empty() {
+        Ok(instance)
+    } else {
+        sort_errors(&mut errors);
+        Err(errors)
+    }
+}
+
+fn sort_errors(errors: &mut [Error]) {
+    errors.sort_by_key(|err| {
+        err.innermost_pos()
+            .map(|pos| (pos.filename.clone(), pos.line, pos.column))
+    });
 }
 
 #[derive(Clone)]
@@ -104,7 +269,7 @@ struct Cursor<'a> {
     cur_pos: Pos,
 }
 
-#[derive(PartialEq, Eq, Clone)]
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
 enum ParaState {
     No,
     Start,
@@ -155,12 +320,13 @@ impl<'a> Cursor<'a> {
             } else {
                 self.cur_pos.column += 1;
             }
+            self.cur_pos.offset += c.len_utf8();
             Some(c)
-        } else if let Some(Item::Text { text, pos }) = self.items.first() {
+        } else if let Some(Item::Text { text, pos, .. }) = self.items.first() {
             self.cur_pos = pos.clone();
             self.items = &self.items[1..];
             self.pending_chars = text.chars();
-            self.pending_chars.next()
+            self.get_char()
         } else {
             None
         }
@@ -192,13 +358,45 @@ impl<'a> Cursor<'a> {
             Some(Item::Element(element)) if &element.tag == tag => {
                 self.pending_chars = "".chars();
                 self.items = &items[1..];
-                self.cur_pos = element.pos.clone();
+                self.cur_pos = element.end.clone();
                 Some(element)
             }
             _ => None,
         }
     }
 
+    /// The position of the next character or element.
+    fn next_pos(&self) -> Pos {
+        if !self.pending_chars.as_str().is_empty() {
+            return self.cur_pos.clone();
+        }
+        match self.items.first() {
+            Some(Item::Text { pos, .. }) => pos.clone(),
+            Some(Item::Element(element)) => element.pos.clone(),
+            None => self.cur_pos.clone(),
+        }
+    }
+
+    /// Describe the next non-whitespace content and its position.
+    fn found(&self) -> (Found, Pos) {
+        let mut c = self.clone();
+        c.skip_ws();
+        if c.at_end() {
+            (Found::End, c.pos())
+        } else if c.peek_char().is_some() {
+            match c.items.first() {
+                Some(Item::Text { pos, .. }) if c.pending_chars.as_str().is_empty() => {
+                    (Found::Text, pos.clone())
+                }
+                _ => (Found::Text, c.pos()),
+            }
+        } else if let Some(Item::Element(element)) = c.items.first() {
+            (Found::Element(element.tag.clone()), element.pos.clone())
+        } else {
+            (Found::End, c.pos())
+        }
+    }
+
     fn at_end(&self) -> bool {
         self.in_para == ParaState::End
             || (self.items.is_empty() && self.pending_chars.as_str().is_empty())
@@ -211,112 +409,547 @@ impl<'a> Cursor<'a> {
     }
 }
 
+/// Identifies an element being matched at a cursor position. Element
+/// types and items are identified by address, which is stable while
+/// a document is being matched.
+#[derive(PartialEq, Eq, Hash)]
+struct MemoKey {
+    element_type: *const ElementType,
+    items: *const Item,
+    nr_items: usize,
+    pending_chars: *const u8,
+    nr_pending_chars: usize,
+    in_para: ParaState,
+    offset: usize,
+}
+
+impl MemoKey {
+    /// Return the key for matching `pattern`, or `None` if it is not
+    /// an element defined in the schema.
+    fn new(schema: &Schema, pattern: &Pattern, cursor: &Cursor) -> Option<Self> {
+        let element_type = match pattern {
+            Pattern::Element(name) => schema.elements.get(name)?,
+            _ => return None,
+        };
+        let pending_chars = cursor.pending_chars.as_str();
+        Some(MemoKey {
+            element_type,
+            items: cursor.items.as_ptr(),
+            nr_items: cursor.items.len(),
+            pending_chars: pending_chars.as_ptr(),
+            nr_pending_chars: pending_chars.len(),
+            in_para: cursor.in_para,
+            offset: cursor.cur_pos.offset,
+        })
+    }
+}
+
+/// The depth of pattern references beyond which `may_backtrack`
+/// gives up.
+const MAX_PATTERN_DEPTH: usize = 16;
+
+/// The results of matching elements, so that backtracking doesn't
+/// validate an element again at the same position (packrat
+/// parsing). Each entry records the instance, the cursor afterwards
+/// and the errors that were reported.
+struct Memo<'a> {
+    results: HashMap<MemoKey, (Instance, Cursor<'a>, Vec<Error>)>,
+    /// The number of enclosing `Choice` alternatives or `Many`
+    /// iterations that may be backtracked out of after matching an
+    /// element. Elements are only worth remembering (i.e. copying)
+    /// inside those.
+    speculative: usize,
+}
+
 pub fn validate_full_doc(
     schema: &Schema,
     pattern: &Pattern,
     doc: &Doc,
     pos: Pos,
+    errors: &mut Vec<Error>,
 ) -> Result<Instance, Error> {
     let mut cursor = Cursor::new(doc, pos);
-    let instance = validate_doc(schema, pattern, true, &mut cursor)?;
+    let mut memo = Memo {
+        results: HashMap::new(),
+        speculative: 0,
+    };
+    let instance = validate_doc(schema, pattern, true, &mut cursor, &mut memo, errors)?;
     cursor.skip_ws();
     if !cursor.at_end() {
-        return Err(Error::Expected(vec![Expected::End], cursor.pos()));
+        let (found, pos) = cursor.found();
+        return Err(Error::Expected(vec![Expected::End], found, pos));
     }
     Ok(instance)
 }
 
-fn validate_doc(
+/// Consume text up to the next element, or to the end of the current
+/// paragraph.
+fn get_text(cursor: &mut Cursor) -> String {
+    let mut text = String::new();
+    let mut in_empty_line = false;
+    while let Some(c) = cursor.get_char() {
+        text.push(c);
+        match cursor.in_para {
+            ParaState::No => {}
+            ParaState::Start => {
+                if !c.is_whitespace() {
+                    cursor.in_para = ParaState::Inside;
+                }
+            }
+            ParaState::Inside => {
+                if c == '\n' {
+                    if in_empty_line {
+                        cursor.in_para = ParaState::End;
+                        break;
+                    }
+                    in_empty_line = true;
+                } else if in_empty_line && !c.is_whitespace() {
+                    in_empty_line = false;
+                }
+            }
+            ParaState::End => panic!(),
+        }
+    }
+    text
+}
+
+/// Parse the text matched by a typed text pattern. Returns `None` if
+/// the text is not a valid value.
+fn parse_value(pattern: &Pattern, text: &str) -> Option<Value> {
+    match pattern {
+        Pattern::Int(min, max) => text
+            .parse::<i64>()
+            .ok()
+            .filter(|n| min.map_or(true, |min| *n >= min) && max.map_or(true, |max| *n <= max))
+            .map(Value::Int),
+        Pattern::Ident => {
+            let mut chars = text.chars();
+            if chars
+                .next()
+                .map_or(false, |c| c.is_ascii_alphabetic() || c == '_')
+                && chars.all(|c| c.is_ascii_alphanumeric() || "_-.:".contains(c))
+            {
+                Some(Value::Ident(text.to_string()))
+            } else {
+                None
+            }
+        }
+        Pattern::Uri => {
+            /* Accept absolute URIs and relative references, but
+            require a valid scheme if there is one. */
+            let scheme_ok = match text.find(':') {
+                Some(n) if !text[..n].contains('/') => {
+                    let mut chars = text[..n].chars();
+                    chars.next().map_or(false, |c| c.is_ascii_alphabetic())
+                        && chars.all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+                }
+                _ => true,
+            };
+            if scheme_ok
+                && !text.is_empty()
+                && !text.chars().any(|c| c.is_whitespace() || c.is_control())
+            {
+                Some(Value::Uri(text.to_string()))
+            } else {
+                None
+            }
+        }
+        Pattern::Enum(keywords) => {
+            if keywords.iter().any(|k| k == text) {
+                Some(Value::Keyword(text.to_string()))
+            } else {
+                None
+            }
+        }
+        Pattern::Regex(re) => {
+            if re.is_match(text) {
+                Some(Value::String(text.to_string()))
+            } else {
+                None
+            }
+        }
+        _ => panic!("not a typed text pattern: {:?}", pattern),
+    }
+}
+
+/// Describe the values matched by a typed text pattern.
+fn describe_value(pattern: &Pattern) -> String {
+    match pattern {
+        Pattern::Int(None, None) => "an integer".to_string(),
+        Pattern::Int(Some(min), None) => format!("an integer of at least {}", min),
+        Pattern::Int(None, Some(max)) => format!("an integer of at most {}", max),
+        Pattern::Int(Some(min), Some(max)) => {
+            format!("an integer between {} and {}", min, max)
+        }
+        Pattern::Ident => "an identifier".to_string(),
+        Pattern::Uri => "a URI".to_string(),
+        Pattern::Enum(keywords) => {
+            let keywords: Vec<String> = keywords.iter().map(|k| format!("'{}'", k)).collect();
+            format!("one of {}", keywords.join(", "))
+        }
+        Pattern::Regex(re) => format!("text matching /{}/", re),
+        _ => panic!("not a typed text pattern: {:?}", pattern),
+    }
+}
+
+/// Validate a document against `pattern`. If it doesn't match,
+/// record the error, remove the offending item and try again, until
+/// the document matches or there is nothing left to remove. In the
+/// latter case, the returned instance is a placeholder.
+fn validate_recovering(
     schema: &Schema,
     pattern: &Pattern,
-    at_top: bool,
-    mut cursor: &mut Cursor,
-) -> Result<Instance, Error> {
-    match pattern {
-        Pattern::Text => {
-            let mut text = String::new();
-            let mut in_empty_line = false;
-            while let Some(c) = cursor.get_char() {
-                text.push(c);
-                match cursor.in_para {
-                    ParaState::No => {}
-                    ParaState::Start => {
-                        if !c.is_whitespace() {
-                            cursor.in_para = ParaState::Inside;
-                        }
+    doc: &Doc,
+    pos: Pos,
+    errors: &mut Vec<Error>,
+) -> Instance {
+    let mut doc = Cow::Borrowed(doc);
+    let mut skipped_any = false;
+    loop {
+        /* Errors found in an attempt that fails are found again in
+        the next attempt. */
+        let mut attempt_errors = vec![];
+        match validate_full_doc(schema, pattern, &doc, pos.clone(), &mut attempt_errors) {
+            Ok(instance) => {
+                errors.append(&mut attempt_errors);
+                return instance;
+            }
+            Err(err) => {
+                /* Missing content after a skipped item is probably
+                due to the skipping, so don't report it. */
+                if skipped_any {
+                    if let Error::Expected(_, Found::End, _) = err {
+                        errors.append(&mut attempt_errors);
+                        return Instance::Seq(vec![]);
                     }
-                    ParaState::Inside => {
-                        if c == '\n' {
-                            if in_empty_line {
-                                cursor.in_para = ParaState::End;
-                                break;
-                            }
-                            in_empty_line = true;
-                        } else if in_empty_line && !c.is_whitespace() {
-                            in_empty_line = false;
-                        }
+                }
+                let skipped = !err.is_fatal() && skip_item(doc.to_mut(), &err);
+                errors.push(err);
+                if !skipped {
+                    errors.append(&mut attempt_errors);
+                    return Instance::Seq(vec![]);
+                }
+                skipped_any = true;
+            }
+        }
+    }
+}
+
+/// Remove the item that caused a validation error from a document:
+/// either an element, or text up to the end of the paragraph. Returns
+/// false if there is no such item.
+fn skip_item(doc: &mut Doc, err: &Error) -> bool {
+    let (found, pos) = match err {
+        Error::Expected(_, found, pos) => (found, pos),
+        _ => return false,
+    };
+    match found {
+        Found::Element(_) => {
+            match doc
+                .iter()
+                .position(|item| matches!(item, Item::Element(element) if element.pos == *pos))
+            {
+                Some(n) => {
+                    doc.remove(n);
+                    true
+                }
+                None => false,
+            }
+        }
+        Found::Text | Found::Value(_) => {
+            for n in 0..doc.len() {
+                if let Item::Text {
+                    text,
+                    pos: start,
+                    end,
+                } = &mut doc[n]
+                {
+                    if start.filename != pos.filename
+                        || pos.offset < start.offset
+                        || pos.offset >= end.offset
+                    {
+                        continue;
+                    }
+                    let from = pos.offset - start.offset;
+                    if !text.is_char_boundary(from) {
+                        continue;
+                    }
+                    let rest = &text[from..];
+                    let ws = rest.len() - rest.trim_start().len();
+                    let to = rest[ws..]
+                        .find("\n\n")
+                        .map_or(text.len(), |i| from + ws + i);
+                    if from == 0 && to == text.len() {
+                        doc.remove(n);
+                    } else {
+                        text.replace_range(from..to, "");
                     }
-                    ParaState::End => panic!(),
+                    return true;
                 }
             }
+            false
+        }
+        Found::End => false,
+    }
+}
+
+/// Validate an argument of `element` against `pattern`, attributing
+/// errors in the argument to the element.
+fn validate_arg(
+    schema: &Schema,
+    pattern: &Pattern,
+    arg: &Doc,
+    element: &Element,
+    errors: &mut Vec<Error>,
+) -> Instance {
+    let mut arg_errors = vec![];
+    let instance = validate_recovering(schema, pattern, arg, element.pos.clone(), &mut arg_errors);
+    for err in arg_errors {
+        errors.push(if err.is_fatal() {
+            err
+        } else {
+            Error::WrongElementContent(element.tag.clone(), element.pos.clone(), Box::new(err))
+        });
+    }
+    instance
+}
+
+fn validate_doc<'a>(
+    schema: &Schema,
+    pattern: &Pattern,
+    at_top: bool,
+    cursor: &mut Cursor<'a>,
+    memo: &mut Memo<'a>,
+    errors: &mut Vec<Error>,
+) -> Result<Instance, Error> {
+    let key = match MemoKey::new(schema, pattern, cursor) {
+        Some(key) => key,
+        None => return match_pattern(schema, pattern, at_top, cursor, memo, errors),
+    };
+    if let Some((instance, c, errs)) = memo.results.get(&key) {
+        *cursor = c.clone();
+        errors.extend(errs.iter().cloned());
+        return Ok(instance.clone());
+    }
+
+    let nr_errors = errors.len();
+    let instance = match_pattern(schema, pattern, at_top, cursor, memo, errors)?;
+    if memo.speculative > 0 {
+        memo.results.insert(
+            key,
+            (
+                instance.clone(),
+                cursor.clone(),
+                errors[nr_errors..].to_vec(),
+            ),
+        );
+    }
+    Ok(instance)
+}
+
+/// Whether matching `pattern` can fail after it has matched an
+/// element, so that the element may be matched again after
+/// backtracking. This is conservative for deeply nested references.
+fn may_backtrack(schema: &Schema, pattern: &Pattern, at_top: bool, depth: usize) -> bool {
+    if depth > MAX_PATTERN_DEPTH {
+        return true;
+    }
+    match pattern {
+        Pattern::Para(pat) => may_backtrack(schema, pat, false, depth),
+        Pattern::Ref(name) => schema
+            .patterns
+            .get(name)
+            .map_or(false, |pat| may_backtrack(schema, pat, at_top, depth + 1)),
+        Pattern::Choice(patterns) => patterns
+            .iter()
+            .any(|pat| may_backtrack(schema, pat, at_top, depth)),
+        Pattern::Many(min, _, pat) => {
+            at_top || *min > 1 || may_backtrack(schema, pat, false, depth)
+        }
+        Pattern::Seq(patterns) => patterns.iter().enumerate().any(|(n, pat)| {
+            let at_top = at_top && n + 1 == patterns.len();
+            may_backtrack(schema, pat, at_top, depth)
+                || (n > 0 && may_fail(schema, pat, at_top, depth))
+        }),
+        _ => false,
+    }
+}
+
+/// Whether matching `pattern` can fail (other than fatally).
+fn may_fail(schema: &Schema, pattern: &Pattern, at_top: bool, depth: usize) -> bool {
+    if depth > MAX_PATTERN_DEPTH {
+        return true;
+    }
+    match pattern {
+        Pattern::Ref(name) => schema
+            .patterns
+            .get(name)
+            .map_or(true, |pat| may_fail(schema, pat, at_top, depth + 1)),
+        Pattern::Choice(patterns) => patterns
+            .iter()
+            .all(|pat| may_fail(schema, pat, at_top, depth)),
+        Pattern::Many(min, _, pat) => at_top || (*min > 0 && may_fail(schema, pat, false, depth)),
+        Pattern::Seq(patterns) => patterns
+            .iter()
+            .enumerate()
+            .any(|(n, pat)| may_fail(schema, pat, at_top && n + 1 == patterns.len(), depth)),
+        _ => true,
+    }
+}
+
+fn match_pattern<'a>(
+    schema: &Schema,
+    pattern: &Pattern,
+    at_top: bool,
+    mut cursor: &mut Cursor<'a>,
+    memo: &mut Memo<'a>,
+    errors: &mut Vec<Error>,
+) -> Result<Instance, Error> {
+    match pattern {
+        Pattern::Text => {
+            let start = cursor.next_pos();
+            let text = get_text(cursor);
             if text.is_empty() {
-                return Err(Error::Expected(vec![Expected::Text], cursor.pos()));
+                let (found, pos) = cursor.found();
+                return Err(Error::Expected(vec![Expected::Text], found, pos));
+            }
+            let end = cursor.pos();
+            return Ok(Instance::Text(text, Range { start, end }));
+        }
+
+        Pattern::Int(_, _)
+        | Pattern::Ident
+        | Pattern::Uri
+        | Pattern::Enum(_)
+        | Pattern::Regex(_) => {
+            let (found, pos) = cursor.found();
+            let start = cursor.next_pos();
+            let text = get_text(cursor);
+            let end = cursor.pos();
+            let text = text.trim();
+            match parse_value(pattern, text) {
+                Some(value) => Ok(Instance::Value(value, Range { start, end })),
+                None => {
+                    let found = if text.is_empty() {
+                        found
+                    } else {
+                        Found::Value(text.to_string())
+                    };
+                    Err(Error::Expected(
+                        vec![Expected::Value(describe_value(pattern))],
+                        found,
+                        pos,
+                    ))
+                }
             }
-            return Ok(Instance::Text(text));
         }
 
         Pattern::Para(pat) => {
             assert!(cursor.in_para == ParaState::No);
             if cursor.at_end_ws() {
-                return Err(Error::Expected(vec![Expected::Para], cursor.pos()));
+                let (found, pos) = cursor.found();
+                return Err(Error::Expected(vec![Expected::Para], found, pos));
             }
+            let (_, start) = cursor.found();
             cursor.in_para = ParaState::Start;
-            let instance = validate_doc(schema, pat, false, cursor)?;
+            let instance = validate_doc(schema, pat, false, cursor, memo, errors)?;
             assert!(cursor.in_para != ParaState::No);
             cursor.in_para = ParaState::No;
             if instance.is_whitespace() {
-                return Err(Error::Expected(vec![Expected::Para], cursor.pos()));
+                let (found, pos) = cursor.found();
+                return Err(Error::Expected(vec![Expected::Para], found, pos));
             } else {
-                return Ok(Instance::Para(Box::new(instance)));
+                let end = cursor.pos();
+                return Ok(Instance::Para(Box::new(instance), Range { start, end }));
             }
         }
 
         Pattern::Element(name) => {
-            if let Some(pos_args_patterns) = schema.elements.get(name) {
+            if let Some(element_type) = schema.elements.get(name) {
                 if let Some(element) = cursor.get_element(name) {
+                    let pos_args_patterns = &element_type.pos_args;
                     let mut instances = vec![];
                     if (pos_args_patterns.len() == 0 && !element.is_empty())
                         || (pos_args_patterns.len() > 0
                             && pos_args_patterns.len() != element.pos_args.len())
                     {
-                        return Err(Error::WrongArgCount(
+                        errors.push(Error::WrongArgCount(
                             name.clone(),
                             pos_args_patterns.len(),
                             element.pos_args.len(),
-                            cursor.pos(),
+                            element.pos.clone(),
                         ));
+                    } else {
+                        for (d, e) in pos_args_patterns.iter().zip(element.pos_args.iter()) {
+                            instances.push(validate_arg(schema, d, e, element, errors));
+                        }
                     }
-                    for (d, e) in pos_args_patterns.iter().zip(element.pos_args.iter()) {
-                        match validate_full_doc(schema, d, e, element.pos.clone()) {
-                            Ok(instance) => instances.push(instance),
-                            Err(err) => {
-                                if err.is_fatal() {
-                                    return Err(err);
-                                } else {
-                                    return Err(Error::WrongElementContent(
-                                        name.to_string(),
-                                        element.pos.clone(),
-                                        Box::new(err),
-                                    ));
-                                }
+
+                    let mut named_args = BTreeMap::new();
+                    let mut arg_names: Vec<&String> = element.named_args.keys().collect();
+                    arg_names.sort();
+                    for arg_name in arg_names {
+                        let arg_pos = element
+                            .named_arg_ranges
+                            .get(arg_name)
+                            .map_or_else(|| element.pos.clone(), |range| range.start.clone());
+                        let decl = match element_type.named_args.get(arg_name) {
+                            Some(decl) => decl,
+                            None => {
+                                errors.push(Error::UnknownNamedArg(
+                                    name.clone(),
+                                    arg_name.clone(),
+                                    arg_pos,
+                                ));
+                                continue;
                             }
                         };
+                        let arg = &element.named_args[arg_name];
+                        named_args.insert(
+                            arg_name.clone(),
+                            validate_arg(schema, &decl.pattern, arg, element, errors),
+                        );
                     }
-                    return Ok(Instance::Element(name.clone(), instances));
+
+                    for (arg_name, decl) in &element_type.named_args {
+                        if element.named_args.contains_key(arg_name) {
+                            continue;
+                        }
+                        if decl.required {
+                            errors.push(Error::MissingNamedArg(
+                                name.clone(),
+                                arg_name.clone(),
+                                element.pos.clone(),
+                            ));
+                        }
+                        if let Some(default) = &decl.default {
+                            let doc = Doc(vec![Item::new_text(
+                                default.clone(),
+                                element.pos.clone(),
+                                element.pos.clone(),
+                            )]);
+                            named_args.insert(
+                                arg_name.clone(),
+                                validate_arg(schema, &decl.pattern, &doc, element, errors),
+                            );
+                        }
+                    }
+
+                    return Ok(Instance::Element(
+                        name.clone(),
+                        named_args,
+                        instances,
+                        Range {
+                            start: element.pos.clone(),
+                            end: element.end.clone(),
+                        },
+                    ));
                 } else {
+                    let (found, pos) = cursor.found();
                     return Err(Error::Expected(
                         vec![Expected::Element(name.clone())],
-                        cursor.pos(),
+                        found,
+                        pos,
                     ));
                 }
             } else {
@@ -332,46 +965,81 @@ fn validate_doc(
                     pat,
                     patterns.len() == n + 1 && at_top,
                     &mut cursor,
+                    memo,
+                    errors,
                 )?);
             }
             return Ok(Instance::Seq(instances));
         }
 
         Pattern::Choice(patterns) => {
-            let mut expected = vec![];
-            let pos = cursor.pos();
+            let (found, pos) = cursor.found();
+            let mut furthest = Error::Expected(vec![], found, pos);
             for (n, pat) in patterns.iter().enumerate() {
                 let mut c = cursor.clone();
-                match validate_doc(schema, pat, at_top, &mut c) {
+                let nr_errors = errors.len();
+                let speculative = n + 1 < patterns.len() && may_backtrack(schema, pat, at_top, 0);
+                memo.speculative += speculative as usize;
+                let result = validate_doc(schema, pat, at_top, &mut c, memo, errors);
+                memo.speculative -= speculative as usize;
+                match result {
                     Ok(instance) => {
-                        mem::replace(cursor, c); // FIXME
+                        *cursor = c;
                         return Ok(Instance::Choice(n, Box::new(instance)));
                     }
                     Err(err) => {
                         if err.is_fatal() {
                             return Err(err);
-                        } else {
-                            match err {
-                                Error::Expected(mut exp, _) => expected.append(&mut exp),
-                                _ => return Err(err),
+                        }
+                        errors.truncate(nr_errors);
+                        /* Report the alternative that got furthest,
+                        or all alternatives that failed at the same
+                        position. */
+                        match (&mut furthest, err) {
+                            (
+                                Error::Expected(expected, found, pos),
+                                Error::Expected(mut exp, found2, pos2),
+                            ) => {
+                                if pos2 == *pos {
+                                    expected.append(&mut exp);
+                                    /* Prefer the more specific
+                                    description of an invalid value. */
+                                    if let Found::Value(_) = found2 {
+                                        *found = found2;
+                                    }
+                                } else if (pos2.line, pos2.column) > (pos.line, pos.column) {
+                                    furthest = Error::Expected(exp, found2, pos2);
+                                }
                             }
+                            (_, err) => return Err(err),
                         }
                     }
                 }
             }
-            return Err(Error::Expected(expected, pos));
+            return Err(furthest);
         }
 
+        Pattern::Ref(name) => match schema.patterns.get(name) {
+            Some(pattern) => validate_doc(schema, pattern, at_top, cursor, memo, errors),
+            None => Err(Error::UndefinedPattern(name.clone())),
+        },
+
         Pattern::Many(min, max, pattern) => {
             let mut instances = vec![];
             let mut done = false;
+            let speculative = may_backtrack(schema, pattern, false, 0) as usize;
             while !done && (max.is_none() || instances.len() < max.unwrap()) && !cursor.at_end_ws()
             {
-                match validate_doc(schema, pattern, false, &mut cursor) {
+                let nr_errors = errors.len();
+                memo.speculative += speculative;
+                let result = validate_doc(schema, pattern, false, &mut cursor, memo, errors);
+                memo.speculative -= speculative;
+                match result {
                     Ok(instance) => {
                         instances.push(instance);
                     }
                     Err(err) => {
+                        errors.truncate(nr_errors);
                         if err.is_fatal()
                             || instances.len() < *min
                             || (at_top && !cursor.at_end_ws())
@@ -386,3 +1054,318 @@ fn validate_doc(
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser;
+
+    fn link_schema() -> Schema {
+        let mut schema = Schema {
+            start: Pattern::many(Pattern::element("link")),
+            patterns: std::collections::HashMap::new(),
+            elements: std::collections::HashMap::new(),
+        };
+        schema.add_element("link", vec![Pattern::Text]);
+        schema.add_named_arg(
+            "link",
+            "target",
+            NamedArg {
+                pattern: Pattern::Text,
+                required: true,
+                default: None,
+            },
+        );
+        schema.add_named_arg(
+            "link",
+            "kind",
+            NamedArg {
+                pattern: Pattern::Text,
+                required: false,
+                default: Some("external".to_string()),
+            },
+        );
+        schema
+    }
+
+    fn level_schema() -> Schema {
+        let mut schema = Schema {
+            start: Pattern::many(Pattern::element("level")),
+            patterns: std::collections::HashMap::new(),
+            elements: std::collections::HashMap::new(),
+        };
+        schema.add_element(
+            "level",
+            vec![Pattern::Choice(vec![
+                Pattern::Int(Some(1), Some(5)),
+                Pattern::Enum(vec!["max".to_string()]),
+            ])],
+        );
+        schema.add_named_arg(
+            "level",
+            "version",
+            NamedArg {
+                pattern: Pattern::Regex(TextRegex::new("v[0-9]+").unwrap()),
+                required: false,
+                default: None,
+            },
+        );
+        schema
+    }
+
+    fn check_with(schema: &Schema, sst: &str) -> Result<Instance, Vec<Error>> {
+        let doc = parser::parse_string(None, sst).unwrap();
+        validate(schema, &doc, Path::new("test.sst"))
+    }
+
+    fn check(sst: &str) -> Result<Instance, Vec<Error>> {
+        check_with(&link_schema(), sst)
+    }
+
+    fn items(instance: &Instance) -> &Vec<Instance> {
+        match instance {
+            Instance::Many(instances) => instances,
+            i => panic!("{:?}", i),
+        }
+    }
+
+    #[test]
+    fn named_args() {
+        let instance = check("\\link[target=https://example.org/]{Example}").unwrap();
+        match &items(&instance)[0] {
+            Instance::Element(_, named_args, _, _) => {
+                match &named_args["target"] {
+                    Instance::Text(s, _) => assert_eq!(s, "https://example.org/"),
+                    i => panic!("{:?}", i),
+                }
+                match &named_args["kind"] {
+                    Instance::Text(s, _) => assert_eq!(s, "external"),
+                    i => panic!("{:?}", i),
+                }
+            }
+            i => panic!("{:?}", i),
+        }
+    }
+
+    #[test]
+    fn named_args_unknown() {
+        let err = &check("\\link[target=foo][targte=bar]{Example}").unwrap_err()[0];
+        assert_eq!(
+            err.to_string(),
+            "\\link does not take a named argument 'targte'"
+        );
+    }
+
+    #[test]
+    fn named_args_missing() {
+        let err = &check("\\link{Example}").unwrap_err()[0];
+        assert_eq!(err.to_string(), "\\link requires a named argument 'target'");
+        assert_eq!(err.pos().unwrap().column, 0);
+    }
+
+    #[test]
+    fn typed_values() {
+        let instance =
+            check_with(&level_schema(), "\\level[version=v2]{ 3 } \\level{max}").unwrap();
+        let mut values = vec![];
+        for level in items(&instance) {
+            match level {
+                Instance::Element(_, named_args, children, _) => {
+                    if let Some(Instance::Value(value, _)) = named_args.get("version") {
+                        values.push(value.clone());
+                    }
+                    match children[0].unchoice() {
+                        Instance::Value(value, _) => values.push(value.clone()),
+                        i => panic!("{:?}", i),
+                    }
+                }
+                i => panic!("{:?}", i),
+            }
+        }
+        assert_eq!(
+            values,
+            vec![
+                Value::String("v2".to_string()),
+                Value::Int(3),
+                Value::Keyword("max".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn typed_values_invalid() {
+        let err = &check_with(&level_schema(), "\\level{7}").unwrap_err()[0];
+        assert_eq!(
+            err.to_string(),
+            "invalid contents of \\level: expected an integer between 1 and 5 or one of 'max', found '7'"
+        );
+        let err = &check_with(&level_schema(), "\\level[version=2]{1}").unwrap_err()[0];
+        assert_eq!(
+            err.to_string(),
+            "invalid contents of \\level: expected text matching /v[0-9]+/, found '2'"
+        );
+    }
+
+    #[test]
+    fn multiple_errors() {
+        let errors = check_with(
+            &level_schema(),
+            "\\level{7}\n\\foo{bar} stray text\n\n\\level{2}\n\\level[versio=v1]{max}\n",
+        )
+        .unwrap_err();
+        let messages: Vec<(String, u32)> = errors
+            .iter()
+            .map(|err| (err.to_string(), err.pos().unwrap().line))
+            .collect();
+        assert_eq!(
+            messages,
+            vec![
+                (
+                    "invalid contents of \\level: expected an integer between 1 and 5 or one of 'max', found '7'".to_string(),
+                    0
+                ),
+                ("expected \\level, found \\foo".to_string(), 1),
+                ("expected \\level, found text".to_string(), 1),
+                ("\\level does not take a named argument 'versio'".to_string(), 4),
+            ]
+        );
+    }
+
+    #[test]
+    fn error_order() {
+        let pos = |line| Pos {
+            filename: None,
+            line,
+            column: 0,
+            offset: 0,
+        };
+        let expected = |line| Error::Expected(vec![Expected::End], Found::Text, pos(line));
+        let mut errors = vec![
+            Error::WrongElementContent("a".to_string(), pos(1), Box::new(expected(4))),
+            expected(2),
+        ];
+        sort_errors(&mut errors);
+        let lines: Vec<u32> = errors
+            .iter()
+            .map(|err| err.innermost_pos().unwrap().line)
+            .collect();
+        assert_eq!(lines, vec![2, 4]);
+    }
+
+    #[test]
+    fn positions() {
+        let instance = check("\\link[target=x]{Example}").unwrap();
+        match &items(&instance)[0] {
+            Instance::Element(_, _, children, range) => {
+                assert_eq!((range.start.offset, range.end.offset), (0, 24));
+                match &children[0] {
+                    Instance::Text(_, range) => {
+                        assert_eq!((range.start.offset, range.end.offset), (16, 23))
+                    }
+                    i => panic!("{:?}", i),
+                }
+            }
+            i => panic!("{:?}", i),
+        }
+    }
+
+    #[test]
+    fn value_positions() {
+        let instance = check_with(&level_schema(), "\\level{ 3}").unwrap();
+        match &items(&instance)[0] {
+            Instance::Element(_, _, children, _) => match children[0].unchoice() {
+                Instance::Value(Value::Int(3), range) => {
+                    assert_eq!((range.start.offset, range.end.offset), (7, 9))
+                }
+                i => panic!("{:?}", i),
+            },
+            i => panic!("{:?}", i),
+        }
+    }
+
+    /// A schema in which both alternatives of `block` start with
+    /// `\a`, so that without memoisation nested `\a` elements are
+    /// validated an exponential number of times.
+    fn backtracking_schema() -> Schema {
+        let mut schema = Schema {
+            start: Pattern::many(Pattern::reference("block")),
+            patterns: std::collections::HashMap::new(),
+            elements: std::collections::HashMap::new(),
+        };
+        schema.patterns.insert(
+            "block".to_string(),
+            Pattern::Choice(vec![
+                Pattern::Seq(vec![Pattern::element("a"), Pattern::element("b")]),
+                Pattern::Seq(vec![Pattern::element("a"), Pattern::element("c")]),
+            ]),
+        );
+        schema.add_element("a", vec![Pattern::many(Pattern::reference("block"))]);
+        schema.add_element("b", vec![]);
+        schema.add_element("c", vec![]);
+        schema
+    }
+
+    fn nested_blocks(depth: usize) -> String {
+        let mut sst = String::new();
+        for _ in 0..depth {
+            sst.push_str("\\a{");
+        }
+        for _ in 0..depth {
+            sst.push_str("} \\c{}");
+        }
+        sst
+    }
+
+    #[test]
+    fn backtracking() {
+        let instance = check_with(&backtracking_schema(), &nested_blocks(3)).unwrap();
+        assert_eq!(items(&instance).len(), 1);
+        assert!(check_with(&backtracking_schema(), "\\a{\\a{} \\c{}} \\d{}").is_err());
+    }
+
+    /// Generate a book with the given number of chapters, each
+    /// containing sections with paragraphs, lists and listings.
+    fn large_book(chapters: usize) -> String {
+        let mut sst = String::from("\\book{A Large Book}{\n");
+        for c in 0..chapters {
+            sst.push_str(&format!("\\chapter[id=ch{}]{{Chapter {}}}{{\n", c, c));
+            for s in 0..20 {
+                sst.push_str(&format!("\\section{{Section {}}}{{\n", s));
+                for _ in 0..8 {
+                    sst.push_str(
+                        "Some \\emph{text} with \\code{code}, a \\link{https://example.org/}{link} \
+                         and a reference to \\xref{ch0}.\nMore text.\n\n",
+                    );
+                }
+                sst.push_str("\\ul{\\li{One} \\li{Two \\strong{2}}}\n\n\\listing{{{x = 1}}}\n\n");
+                sst.push_str("\\subsection{Sub}{Text.\n\n\\namedlist{\\item{A}{B}}}\n}\n");
+            }
+            sst.push_str("}\n");
+        }
+        sst.push('}');
+        sst
+    }
+
+    fn bench(name: &str, schema: &Schema, sst: &str) {
+        let doc = parser::parse_string(None, sst).unwrap();
+        let start = std::time::Instant::now();
+        let result = validate(schema, &doc, Path::new("bench.sst"));
+        eprintln!("{}: {:?}", name, start.elapsed());
+        assert!(result.is_ok());
+    }
+
+    /* Benchmarks, to be run with `cargo test --release -- --ignored
+    bench_`. */
+
+    #[test]
+    #[ignore]
+    fn bench_large_book() {
+        bench("large book", &crate::core::SCHEMA, &large_book(50));
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_backtracking() {
+        bench("backtracking", &backtracking_schema(), &nested_blocks(20));
+    }
+}
@@ -0,0 +1,192 @@
+/* A view of validated documents that hides the structure of the
+validation proof (the `Seq`, `Choice` and `Many` nodes that record
+how the schema was matched), so that consumers can access elements
+and their arguments by name and in document order. */
+
+use crate::validate::{Instance, Value};
+use std::collections::BTreeMap;
+
+/// Elements whose first argument is their title.
+const TITLED: &[&str] = &[
+    "book",
+    "article",
+    "part",
+    "chapter",
+    "section",
+    "subsection",
+    "simplesect",
+];
+
+/// A sequence of nodes, such as the contents of an argument.
+#[derive(Clone, Copy, Debug)]
+pub struct Content<'doc>(&'doc [Instance]);
+
+#[derive(Clone, Copy, Debug)]
+pub enum Node<'doc> {
+    Text(&'doc str),
+    Value(&'doc Value),
+    Para(Content<'doc>),
+    Element(Element<'doc>),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Element<'doc> {
+    instance: &'doc Instance,
+    tag: &'doc str,
+    named_args: &'doc BTreeMap<String, Instance>,
+    args: &'doc [Instance],
+}
+
+impl<'doc> Content<'doc> {
+    pub fn new(instance: &'doc Instance) -> Self {
+        Content(std::slice::from_ref(instance))
+    }
+
+    /// Return the nodes in document order.
+    pub fn nodes(&self) -> Vec<Node<'doc>> {
+        let mut nodes = vec![];
+        for instance in self.0 {
+            flatten(instance, &mut nodes);
+        }
+        nodes
+    }
+
+    /// Return the elements in document order.
+    pub fn elements(&self) -> impl Iterator<Item = Element<'doc>> {
+        self.nodes().into_iter().filter_map(|node| match node {
+            Node::Element(element) => Some(element),
+            _ => None,
+        })
+    }
+
+    /// Return the elements with the given tag.
+    pub fn children(&self, tag: &'doc str) -> impl Iterator<Item = Element<'doc>> {
+        self.elements().filter(move |element| element.tag == tag)
+    }
+
+    /// Return the text in the content, ignoring markup.
+    pub fn plain_text(&self) -> String {
+        let mut text = String::new();
+        for instance in self.0 {
+            instance.plain_text(&mut text);
+        }
+        text
+    }
+}
+
+fn flatten<'doc>(instance: &'doc Instance, nodes: &mut Vec<Node<'doc>>) {
+    match instance {
+        Instance::Text(text, _) => nodes.push(Node::Text(text)),
+        Instance::Value(value, _) => nodes.push(Node::Value(value)),
+        Instance::Para(para, _) => nodes.push(Node::Para(Content::new(para))),
+        Instance::Element(_, _, _, _) => nodes.push(Node::Element(Element::new(instance).unwrap())),
+        Instance::Choice(_, instance) => flatten(instance, nodes),
+        Instance::Seq(instances) | Instance::Many(instances) => {
+            for instance in instances {
+                flatten(instance, nodes);
+            }
+        }
+    }
+}
+
+impl<'doc> Element<'doc> {
+    /// Return a view of the instance if it is an element.
+    pub fn new(instance: &'doc Instance) -> Option<Self> {
+        match instance {
+            Instance::Element(tag, named_args, args, _) => Some(Element {
+                instance,
+                tag,
+                named_args,
+                args,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn tag(&self) -> &'doc str {
+        self.tag
+    }
+
+    /// Return the underlying instance, e.g. for looking up its
+    /// number in `number::Numbers`.
+    pub fn instance(&self) -> &'doc Instance {
+        self.instance
+    }
+
+    /// Return the contents of the `n`th positional argument, which is
+    /// empty if there is no such argument.
+    pub fn arg(&self, n: usize) -> Content<'doc> {
+        Content(self.args.get(n..n + 1).unwrap_or(&[]))
+    }
+
+    pub fn named_arg(&self, name: &str) -> Option<Content<'doc>> {
+        self.named_args.get(name).map(Content::new)
+    }
+
+    /// Return the `id` argument of the element.
+    pub fn id(&self) -> Option<String> {
+        self.named_arg("id")
+            .map(|id| id.plain_text().trim().to_string())
+    }
+
+    /// Return the title of a sectioning element.
+    pub fn title(&self) -> Option<Content<'doc>> {
+        if TITLED.contains(&self.tag) {
+            Some(self.arg(0))
+        } else {
+            None
+        }
+    }
+
+    /// Return the elements with the given tag in the positional
+    /// arguments.
+    pub fn children(&self, tag: &'doc str) -> impl Iterator<Item = Element<'doc>> {
+        Content(self.args).children(tag)
+    }
+
+    /// Return the contents of the element, i.e. its last positional
+    /// argument.
+    pub fn body(&self) -> Content<'doc> {
+        self.arg(self.args.len().saturating_sub(1))
+    }
+}
+
+/// Parse `sst`, validate it against the core schema and pass the
+/// result to `render`. Used by the tests of the output backends.
+#[cfg(test)]
+pub fn convert<T>(sst: &str, render: impl FnOnce(&Instance) -> T) -> T {
+    let doc = crate::parser::parse_string(None, sst).unwrap();
+    let instance =
+        crate::validate::validate(&crate::core::SCHEMA, &doc, std::path::Path::new("test.sst"))
+            .unwrap();
+    render(&instance)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{core, parser, validate};
+    use std::path::Path;
+
+    #[test]
+    fn view() {
+        let doc = parser::parse_string(
+            None,
+            "\\chapter[id=intro]{The \\emph{Intro}}{Hello.\n\n\\ul{\\li{One} \\li{Two}}}",
+        )
+        .unwrap();
+        let instance = validate::validate(&core::SCHEMA, &doc, Path::new("")).unwrap();
+        let chapter = Content::new(&instance).elements().next().unwrap();
+        assert_eq!(chapter.tag(), "chapter");
+        assert_eq!(chapter.id(), Some("intro".to_string()));
+        assert_eq!(chapter.title().unwrap().plain_text(), "The Intro");
+        assert!(chapter.arg(2).nodes().is_empty());
+        let ul = chapter.children("ul").next().unwrap();
+        let items: Vec<_> = ul
+            .arg(0)
+            .children("li")
+            .map(|li| li.arg(0).plain_text())
+            .collect();
+        assert_eq!(items, vec!["One", "Two"]);
+    }
+}
@@ -0,0 +1,140 @@
+use crate::ast::*;
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// An id that was already defined, its position and the position
+    /// of the first definition.
+    DuplicateId(String, Pos, Pos),
+    UnresolvedXref(String, Pos),
+}
+
+impl Error {
+    pub fn pos(&self) -> &Pos {
+        match self {
+            Error::DuplicateId(_, pos, _) => pos,
+            Error::UnresolvedXref(_, pos) => pos,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::DuplicateId(id, _, _) => write!(f, "duplicate id '{}'", id),
+            Error::UnresolvedXref(id, _) => {
+                write!(f, "cross-reference to undefined id '{}'", id)
+            }
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+/// Check that the `id` arguments in a document are unique and that
+/// every `\xref` refers to one of them.
+pub fn check(doc: &Doc) -> Result<(), Vec<Error>> {
+    let mut ids = HashMap::new();
+    let mut xrefs = vec![];
+    let mut errors = vec![];
+    collect(doc, &mut ids, &mut xrefs, &mut errors);
+
+    for (id, pos) in xrefs {
+        if !ids.contains_key(&id) {
+            errors.push(Error::UnresolvedXref(id, pos));
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+fn collect(
+    doc: &Doc,
+    ids: &mut HashMap<String, Pos>,
+    xrefs: &mut Vec<(String, Pos)>,
+    errors: &mut Vec<Error>,
+) {
+    for item in doc.iter() {
+        if let Item::Element(element) = item {
+            if let Some(id) = element.named_args.get("id") {
+                let pos = element
+                    .named_arg_ranges
+                    .get("id")
+                    .map_or_else(|| element.pos.clone(), |range| range.start.clone());
+                let id = text_of(id);
+                match ids.get(&id) {
+                    Some(first_pos) => {
+                        errors.push(Error::DuplicateId(id, pos, first_pos.clone()));
+                    }
+                    None => {
+                        ids.insert(id, pos);
+                    }
+                }
+            }
+
+            if element.tag == "xref" && element.pos_args.len() == 1 {
+                xrefs.push((text_of(&element.pos_args[0]), element.pos.clone()));
+            }
+
+            for arg in element.named_args.values() {
+                collect(arg, ids, xrefs, errors);
+            }
+            for arg in &element.pos_args {
+                collect(arg, ids, xrefs, errors);
+            }
+        }
+    }
+}
+
+/// Return the text of an argument that should consist only of text,
+/// without surrounding whitespace.
+fn text_of(doc: &Doc) -> String {
+    doc.iter()
+        .filter_map(Item::get_text)
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser;
+
+    fn check_str(sst: &str) -> Result<(), Vec<Error>> {
+        check(&parser::parse_string(None, sst).unwrap())
+    }
+
+    #[test]
+    fn xref_ok() {
+        assert_eq!(
+            check_str("\\section[id=foo]{Foo}{See \\xref{foo}.}"),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn xref_errors() {
+        let errors =
+            check_str("\\section[id=foo]{Foo}{\\listing[id=foo]{x} \\xref{bar}}").unwrap_err();
+        assert_eq!(errors.len(), 2);
+        match &errors[0] {
+            Error::DuplicateId(id, pos, first_pos) => {
+                assert_eq!(id, "foo");
+                assert_eq!(pos.column, 30);
+                assert_eq!(first_pos.column, 8);
+            }
+            err => panic!("{:?}", err),
+        }
+        match &errors[1] {
+            Error::UnresolvedXref(id, _) => assert_eq!(id, "bar"),
+            err => panic!("{:?}", err),
+        }
+    }
+}
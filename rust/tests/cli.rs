@@ -0,0 +1,43 @@
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+fn run_with_stdin(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_sst"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    /* The command may exit without reading its input. */
+    let _ = child.stdin.take().unwrap().write_all(stdin.as_bytes());
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn fmt_stdin() {
+    let output = run_with_stdin(&["fmt", "-"], "Hello \\emph{x}\n");
+    assert!(output.status.success(), "{:?}", output);
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "Hello \\emph{x}\n"
+    );
+}
+
+#[test]
+fn fmt_stdin_in_place() {
+    let output = run_with_stdin(&["fmt", "-i", "-"], "Hello\n");
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("cannot format standard input in place"));
+}
+
+#[test]
+fn stdin_snippet() {
+    let output = run_with_stdin(&["eval", "-"], "Hello \\if{x}\n");
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("1 | Hello \\if{x}\n"));
+}